@@ -0,0 +1,494 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use super::blocking::unblock;
+use super::client::DEFAULT_TIMEOUT;
+use super::endpoint::Endpoint;
+use super::errors::Error;
+use super::models::{
+    AlipayClientSecret, CashierPaymentFull, CashierPaymentInquiry, CashierPaymentRefundFull,
+    CashierPaymentRefundInquiry, CashierPaymentRefundSimple, CashierPaymentSimple, HasEndpoint,
+    Response, Signable, WebhookData, WebhookResponse, WebhookResponseInput,
+};
+use super::notification::Notification;
+use super::replay::WebhookPolicy;
+use super::resolve::{Outcome, RequestBody, Resolution, ResolvePolicy};
+use super::response::check_response;
+use super::retry::{settle, Attempts, RetryPolicy};
+use super::sansio::AlipayProtocol;
+use super::transport::{AsyncTransport, ReqwestTransport};
+
+/// The async counterpart of [`AlipayClient`](crate::AlipayClient), built on `reqwest`.
+///
+/// It shares the models, signing, response parsing and retries with the blocking
+/// client. Signing, verifying and recording notifications run on tokio's blocking
+/// pool, as a signer may run a command or talk to an agent, and keys and the replay
+/// store may be files.
+///
+/// ```no_run
+/// # async fn run(secret: alipay_global::models::AlipayClientSecret) {
+/// use alipay_global::AsyncAlipayClient;
+/// use alipay_global::models::CashierPaymentInquiry;
+///
+/// let client = AsyncAlipayClient::new(&secret).unwrap();
+/// let inquiry = CashierPaymentInquiry {
+///     payment_request_id: Some(String::from("order-1")),
+///     payment_id: None,
+/// };
+/// let r = client.inquire(&inquiry).await;
+/// # }
+/// ```
+pub struct AsyncAlipayClient {
+    protocol: Arc<AlipayProtocol>,
+    transport: Box<dyn AsyncTransport>,
+    retry_policy: Arc<RetryPolicy>,
+}
+
+impl AsyncAlipayClient {
+    /// Build a client from a secret, loading and parsing both keys once.
+    pub fn new(secret: &AlipayClientSecret) -> Result<Self, Error> {
+        let http = reqwest::Client::builder()
+            .timeout(DEFAULT_TIMEOUT)
+            .build()?;
+        Self::with_http_client(secret, http)
+    }
+
+    /// Build a client that sends requests through the given `reqwest::Client`.
+    pub fn with_http_client(
+        secret: &AlipayClientSecret,
+        http: reqwest::Client,
+    ) -> Result<Self, Error> {
+        Self::with_transport(secret, ReqwestTransport::new(http))
+    }
+
+    /// Build a client that sends requests through a custom [`AsyncTransport`].
+    pub fn with_transport(
+        secret: &AlipayClientSecret,
+        transport: impl AsyncTransport + 'static,
+    ) -> Result<Self, Error> {
+        Ok(Self::with_protocol(AlipayProtocol::new(secret)?, transport))
+    }
+
+    /// Build a client on a protocol whose keys were loaded some other way
+    pub fn with_protocol(
+        protocol: AlipayProtocol,
+        transport: impl AsyncTransport + 'static,
+    ) -> Self {
+        Self {
+            protocol: Arc::new(protocol),
+            transport: Box::new(transport),
+            retry_policy: Arc::new(RetryPolicy::none()),
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        self.protocol.client_id()
+    }
+
+    pub fn is_sandbox(&self) -> bool {
+        self.protocol.is_sandbox()
+    }
+
+    /// Retry transient failures as described by `retry_policy`.
+    /// Requests are sent only once by default.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = Arc::new(retry_policy);
+    }
+
+    /// How notifications are checked for freshness and replays.
+    /// Neither is checked by default, see [`WebhookPolicy::fresh`].
+    pub fn set_webhook_policy(&mut self, webhook_policy: WebhookPolicy) {
+        Arc::make_mut(&mut self.protocol).set_webhook_policy(webhook_policy);
+    }
+
+    /// The sans-IO layer this client signs and verifies with
+    pub fn protocol(&self) -> &AlipayProtocol {
+        &self.protocol
+    }
+
+    /// Create A [Cashier Payment](https://global.alipay.com/docs/ac/ams/payment_cashier)
+    pub async fn pay(&self, cashier_payment: &CashierPaymentSimple) -> Result<Response, Error> {
+        self.execute(&CashierPaymentFull::from(cashier_payment))
+            .await
+    }
+
+    /// [Inquire](https://global.alipay.com/docs/ac/ams/paymentri_online) the status of a payment
+    pub async fn inquire(
+        &self,
+        cashier_payment_inquiry: &CashierPaymentInquiry,
+    ) -> Result<Response, Error> {
+        self.execute(cashier_payment_inquiry).await
+    }
+
+    /// [Refund](https://global.alipay.com/docs/ac/ams/refund_online) a payment
+    pub async fn refund(
+        &self,
+        cashier_payment_refund: &CashierPaymentRefundSimple,
+    ) -> Result<Response, Error> {
+        self.execute(&CashierPaymentRefundFull::from(cashier_payment_refund))
+            .await
+    }
+
+    /// [Inquire](https://global.alipay.com/docs/ac/ams/ir_online) the status of a refund
+    pub async fn inquire_refund(
+        &self,
+        cashier_payment_refund_inquiry: &CashierPaymentRefundInquiry,
+    ) -> Result<Response, Error> {
+        self.execute(cashier_payment_refund_inquiry).await
+    }
+
+    /// Create a payment, and inquire it until its outcome is definitive
+    /// if the result is unknown or no response came back.
+    /// `PAYMENT_IN_PROCESS` is returned as [`Outcome::Processing`] without inquiring,
+    /// the buyer has to pay on the cashier page first.
+    pub async fn pay_and_resolve(
+        &self,
+        cashier_payment: &CashierPaymentSimple,
+        resolve_policy: &ResolvePolicy,
+    ) -> Outcome {
+        self.resolve(Resolution::payment(cashier_payment, resolve_policy))
+            .await
+    }
+
+    /// Refund a payment, and inquire the refund until its outcome is definitive
+    /// if the result is unknown or no response came back
+    pub async fn refund_and_resolve(
+        &self,
+        cashier_payment_refund: &CashierPaymentRefundSimple,
+        resolve_policy: &ResolvePolicy,
+    ) -> Outcome {
+        self.resolve(Resolution::refund(cashier_payment_refund, resolve_policy))
+            .await
+    }
+
+    async fn resolve(&self, mut resolution: Resolution<'_>) -> Outcome {
+        loop {
+            let (endpoint, body) = resolution.request();
+            let sent = self.call_unchecked(endpoint, body).await;
+            match resolution.next(sent) {
+                ControlFlow::Break(outcome) => return outcome,
+                ControlFlow::Continue(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    /// Verify a notification sent by Alipay and parse its body
+    pub async fn verify_webhook(&self, webhook_data: &WebhookData) -> Result<Response, Error> {
+        let webhook_data = webhook_data.clone();
+        unblock(&self.protocol, move |protocol| {
+            protocol.verify_webhook(&webhook_data)
+        })
+        .await
+    }
+
+    /// Verify a notification sent by Alipay and parse it according to its type
+    pub async fn verify_notification(
+        &self,
+        webhook_data: &WebhookData,
+    ) -> Result<Notification, Error> {
+        let webhook_data = webhook_data.clone();
+        unblock(&self.protocol, move |protocol| {
+            protocol.verify_notification(&webhook_data)
+        })
+        .await
+    }
+
+    /// Forget a notification, so its next delivery is accepted, e.g. when handling it failed
+    pub async fn forget_webhook(&self, webhook_data: &WebhookData) -> Result<(), Error> {
+        let webhook_data = webhook_data.clone();
+        unblock(&self.protocol, move |protocol| {
+            protocol.forget_webhook(&webhook_data)
+        })
+        .await
+    }
+
+    /// Build the signed acknowledgement telling Alipay the notification was handled
+    pub async fn webhook_success_response(
+        &self,
+        webhook_response_in: WebhookResponseInput,
+    ) -> Result<WebhookResponse, Error> {
+        unblock(&self.protocol, move |protocol| {
+            protocol.webhook_success_response(webhook_response_in)
+        })
+        .await
+    }
+
+    /// Build the signed acknowledgement asking Alipay to deliver the notification again
+    pub async fn webhook_failed_response(
+        &self,
+        webhook_response_in: WebhookResponseInput,
+    ) -> Result<WebhookResponse, Error> {
+        unblock(&self.protocol, move |protocol| {
+            protocol.webhook_failed_response(webhook_response_in)
+        })
+        .await
+    }
+
+    /// Call the API the request body belongs to
+    pub async fn execute(&self, body: &impl HasEndpoint) -> Result<Response, Error> {
+        self.call(body.endpoint(), body).await
+    }
+
+    /// Sign the request body, send it to `endpoint`, then verify and parse the response
+    pub async fn call(&self, endpoint: Endpoint, body: &impl Signable) -> Result<Response, Error> {
+        check_response(self.call_unchecked(endpoint, body).await?)
+    }
+
+    /// Like `call`, but a failed result is returned as a response, not an error
+    async fn call_unchecked(
+        &self,
+        endpoint: Endpoint,
+        body: &impl Signable,
+    ) -> Result<Response, Error> {
+        let body = RequestBody(body.get_value());
+        let mut attempts = Attempts::new(&self.retry_policy);
+        loop {
+            let signable = body.clone();
+            let prepared = unblock(&self.protocol, move |protocol| {
+                protocol.prepare_request(endpoint, &signable)
+            })
+            .await?;
+            let path = prepared.path.clone();
+            let sent = self.transport.send(prepared.into()).await;
+            let policy = self.retry_policy.clone();
+            let settled = unblock(&self.protocol, move |protocol| {
+                Ok(settle(&policy, protocol, &path, sent))
+            })
+            .await?;
+            match attempts.next(settled) {
+                ControlFlow::Break(result) => return result,
+                ControlFlow::Continue(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Mutex};
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::models::{load_private_key, HasPrivateKey, HasPublicKey};
+    use crate::signer::{RsaSigner, Signer};
+    use crate::test_support::{fixture, merchant_secret, FakeAlipay};
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    fn inquiry() -> CashierPaymentInquiry {
+        CashierPaymentInquiry {
+            payment_request_id: Some(String::from("order-1")),
+            payment_id: None,
+        }
+    }
+
+    fn refund() -> CashierPaymentRefundSimple {
+        CashierPaymentRefundSimple {
+            refund_request_id: String::from("refund-1"),
+            payment_id: String::from("payment-1"),
+            amount: 100,
+            currency: String::from("HKD"),
+        }
+    }
+
+    fn cashier_payment() -> CashierPaymentSimple {
+        CashierPaymentSimple {
+            payment_request_id: String::from("order-1"),
+            currency: String::from("HKD"),
+            amount: 100,
+            redict_url: String::from("https://example.com/return"),
+            notifiy_url: String::from("https://example.com/notify"),
+            order_description: String::from("order"),
+            reference_order_id: None,
+            terminal_type: None,
+        }
+    }
+
+    fn resolve_policy() -> ResolvePolicy {
+        ResolvePolicy {
+            timeout: Duration::from_secs(5),
+            initial_interval: Duration::from_millis(1),
+            ..ResolvePolicy::default()
+        }
+    }
+
+    #[test]
+    fn futures_can_be_spawned_on_a_multi_threaded_runtime() {
+        let client = AsyncAlipayClient::new(&merchant_secret()).unwrap();
+        assert_send(&client.inquire(&inquiry()));
+        assert_send(&client.refund_and_resolve(&refund(), &ResolvePolicy::default()));
+    }
+
+    #[tokio::test]
+    async fn sends_signed_request_through_transport() {
+        let fake = Arc::new(FakeAlipay::new(vec![json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+            "paymentStatus": "SUCCESS"
+        })]));
+        let client = AsyncAlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+
+        let response = client.inquire(&inquiry()).await.unwrap();
+        assert!(response.is_success());
+
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(
+            requests[0].url,
+            "https://open-global.alipay.com/ams/sandbox/api/v1/payments/inquiryPayment"
+        );
+        assert_eq!(requests[0].header("client-id"), Some("SANDBOX_TEST"));
+        assert!(requests[0].header("signature").is_some());
+        assert!(requests[0].header("request-time").is_some());
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_with_the_same_body() {
+        let fake = Arc::new(FakeAlipay::with_results(vec![
+            Err(Error::transport("connection reset")),
+            Ok(json!({
+                "result": {"resultCode": "UNKNOWN_EXCEPTION", "resultStatus": "U", "resultMessage": "unknown"}
+            })),
+            Ok(json!({
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+            })),
+        ]));
+        let mut client =
+            AsyncAlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+        client.set_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+
+        assert!(client.inquire(&inquiry()).await.unwrap().is_success());
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.body == requests[0].body));
+    }
+
+    #[tokio::test]
+    async fn rejects_response_not_signed_by_alipay() {
+        let mut fake = FakeAlipay::new(vec![json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+        })]);
+        fake.signer =
+            RsaSigner::new(load_private_key(&fixture("merchant_private_key.pem")).unwrap());
+        let client = AsyncAlipayClient::with_transport(&merchant_secret(), fake).unwrap();
+
+        assert!(matches!(
+            client.inquire(&inquiry()).await,
+            Err(Error::Signature { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn pay_and_resolve_inquires_until_the_status_is_final() {
+        let fake = Arc::new(FakeAlipay::with_results(vec![
+            Err(Error::transport("timed out")),
+            Ok(json!({
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+                "paymentStatus": "PROCESSING"
+            })),
+            Ok(json!({
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+                "paymentStatus": "SUCCESS"
+            })),
+        ]));
+        let client = AsyncAlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+
+        let outcome = client
+            .pay_and_resolve(&cashier_payment(), &resolve_policy())
+            .await;
+        assert!(outcome.is_succeeded());
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].url.ends_with("/v1/payments/inquiryPayment"));
+        assert!(requests[1].body.contains(r#""paymentRequestId":"order-1""#));
+    }
+
+    #[tokio::test]
+    async fn pay_and_resolve_does_not_inquire_when_signing_fails() {
+        struct Down;
+        impl Signer for Down {
+            fn sign_digest(&self, _: &[u8; 32]) -> Result<Vec<u8>, Error> {
+                Err(Error::signing("the signing agent is down"))
+            }
+        }
+        let secret = merchant_secret();
+        let protocol =
+            AlipayProtocol::with_signer(&secret, Down, secret.get_public_key().unwrap()).unwrap();
+        let fake = Arc::new(FakeAlipay::new(Vec::new()));
+        let client = AsyncAlipayClient::with_protocol(protocol, fake.clone());
+
+        let outcome = client
+            .pay_and_resolve(&cashier_payment(), &resolve_policy())
+            .await;
+        assert!(matches!(outcome, Outcome::Error(Error::Signature { .. })));
+        assert!(fake.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn signs_off_the_runtime_thread() {
+        /// Signs only once the test tells it to, which it cannot if signing blocks the runtime
+        struct Waiting {
+            go: Mutex<mpsc::Receiver<()>>,
+            signer: RsaSigner,
+        }
+        impl Signer for Waiting {
+            fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+                let go = self.go.lock().unwrap();
+                go.recv_timeout(Duration::from_secs(5))
+                    .map_err(|_| Error::signing("the runtime thread was blocked"))?;
+                self.signer.sign_digest(digest)
+            }
+        }
+        let (tx, rx) = mpsc::channel();
+        let secret = merchant_secret();
+        let waiting = Waiting {
+            go: Mutex::new(rx),
+            signer: RsaSigner::new(secret.get_private_key().unwrap()),
+        };
+        let protocol =
+            AlipayProtocol::with_signer(&secret, waiting, secret.get_public_key().unwrap())
+                .unwrap();
+        let fake = Arc::new(FakeAlipay::new(vec![json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+        })]));
+        let client = AsyncAlipayClient::with_protocol(protocol, fake);
+
+        // the test runtime has a single thread
+        let inquiry = inquiry();
+        let (response, _) = tokio::join!(client.inquire(&inquiry), async {
+            tokio::task::yield_now().await;
+            tx.send(()).unwrap();
+        });
+        assert!(response.unwrap().is_success());
+    }
+
+    #[tokio::test]
+    async fn refund_and_resolve_gives_up_at_the_deadline() {
+        let processing = json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+            "refundStatus": "PROCESSING"
+        });
+        let fake = Arc::new(FakeAlipay::new(vec![
+            json!({
+                "result": {"resultCode": "REFUND_IN_PROCESS", "resultStatus": "U", "resultMessage": "in process"}
+            }),
+            processing.clone(),
+            processing.clone(),
+            processing,
+        ]));
+        let client = AsyncAlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+        let policy = ResolvePolicy {
+            timeout: Duration::from_millis(35),
+            initial_interval: Duration::from_millis(15),
+            multiplier: 1.0,
+            ..ResolvePolicy::default()
+        };
+
+        let outcome = client.refund_and_resolve(&refund(), &policy).await;
+        assert!(matches!(outcome, Outcome::Processing(_)));
+        assert!(fake.requests.lock().unwrap().len() <= 4);
+    }
+}
@@ -0,0 +1,26 @@
+//! Run protocol work that may block off the async runtime.
+//!
+//! Signing may run a command or talk to an agent over a socket, keys may be read
+//! again from files, and a replay store may read and write a file. None of it may
+//! hold up a runtime thread, so the async code runs it on tokio's blocking pool.
+
+use std::sync::Arc;
+
+use super::errors::Error;
+use super::sansio::AlipayProtocol;
+
+/// Run `f` on the blocking pool, a panic in it is resumed here
+pub(crate) async fn unblock<T, F>(protocol: &Arc<AlipayProtocol>, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&AlipayProtocol) -> Result<T, Error> + Send + 'static,
+{
+    let protocol = protocol.clone();
+    match tokio::task::spawn_blocking(move || f(&protocol)).await {
+        Ok(result) => result,
+        Err(e) => match e.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(e) => Err(Error::config_caused_by("the runtime is shutting down", e)),
+        },
+    }
+}
@@ -0,0 +1,407 @@
+//! What to do with a result, built from Alipay's guidance for every
+//! [result code](https://global.alipay.com/docs/ac/ams/payment_cashier#XjIqM).
+
+use super::errors::{Error, SignatureStage, WebhookError};
+use super::models::ResultCode;
+
+/// How a result should be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handling {
+    /// The call succeeded
+    Success,
+    /// A transient failure, send the same request again
+    Retry,
+    /// The outcome is not known yet, inquire the request before doing anything else
+    Inquire,
+    /// A final business failure, e.g. the buyer was declined. Do not retry.
+    Decline,
+    /// The request is wrong and will fail again until the code is fixed
+    InvalidRequest,
+    /// The merchant account, contract or keys need fixing
+    Misconfigured,
+}
+
+impl ResultCode {
+    fn guidance(&self) -> (Handling, &'static str, &'static str) {
+        use Handling::*;
+        match self {
+            ResultCode::SUCCESS => (Success, "The call succeeded.", "No action is needed."),
+            ResultCode::ACCESS_DENIED => (
+                Misconfigured,
+                "Access is denied.",
+                "Contact Alipay Technical Support for detailed reasons.",
+            ),
+            ResultCode::INVALID_API => (
+                InvalidRequest,
+                "The called API is invalid or not active.",
+                "Check the API path, or contact Alipay Technical Support to activate the API.",
+            ),
+            ResultCode::CURRENCY_NOT_SUPPORT => (
+                InvalidRequest,
+                "The currency is not supported.",
+                "Use a currency the contract supports, or contact Alipay Technical Support.",
+            ),
+            ResultCode::EXPIRED_CODE => (
+                Decline,
+                "The payment code is expired.",
+                "Ask the buyer to refresh the payment code.",
+            ),
+            ResultCode::FRAUD_REJECT => (
+                Decline,
+                "The transaction cannot be further processed because of fraud risk.",
+                "Do not retry. Contact Alipay Technical Support if the buyer disputes it.",
+            ),
+            ResultCode::INVALID_ACCESS_TOKEN => (
+                InvalidRequest,
+                "The access token is expired, revoked, or does not exist.",
+                "Obtain a new access token from the buyer.",
+            ),
+            ResultCode::INVALID_CONTRACT => (
+                Misconfigured,
+                "The parameters in the contract do not match those in the current transaction.",
+                "Check the contract parameters, or contact Alipay Technical Support.",
+            ),
+            ResultCode::INVALID_MERCHANT_STATUS => (
+                Misconfigured,
+                "Restrictions are imposed on the merchant.",
+                "Contact Alipay Technical Support for detailed reasons.",
+            ),
+            ResultCode::INVALID_PAYMENT_CODE => (
+                Decline,
+                "The payment code is not supported.",
+                "Ask the buyer to use another payment code or payment method.",
+            ),
+            ResultCode::INVALID_PAYMENT_METHOD_META_DATA => (
+                InvalidRequest,
+                "The payment method meta data is invalid.",
+                "Check the payment method meta data in the request.",
+            ),
+            ResultCode::KEY_NOT_FOUND => (
+                Misconfigured,
+                "The private key or public key of Alipay or the merchant is not found.",
+                "Check that the keys are uploaded on Alipay Developer Center.",
+            ),
+            ResultCode::MERCHANT_KYB_NOT_QUALIFIED => (
+                Misconfigured,
+                "The payment failed because the merchant is not qualified in the KYB check.",
+                "Contact Alipay Technical Support to complete the KYB.",
+            ),
+            ResultCode::MERCHANT_NOT_REGISTERED => (
+                Misconfigured,
+                "The merchant is not registered.",
+                "Register the merchant first, or contact Alipay Technical Support.",
+            ),
+            ResultCode::NO_INTERFACE_DEF => (
+                InvalidRequest,
+                "The API is not defined.",
+                "Check the API path.",
+            ),
+            ResultCode::NO_PAY_OPTIONS => (
+                Decline,
+                "No payment method is available.",
+                "Ask the buyer to use another payment method.",
+            ),
+            ResultCode::ORDER_IS_CANCELED => (
+                Decline,
+                "The transaction is cancelled.",
+                "Create a new payment with a new payment request ID.",
+            ),
+            ResultCode::ORDER_IS_CLOSED => (
+                Decline,
+                "The transaction is closed.",
+                "Create a new payment with a new payment request ID.",
+            ),
+            ResultCode::PARAM_ILLEGAL => (
+                InvalidRequest,
+                "The required parameters are not passed, or illegal parameters exist.",
+                "Check the request parameters against the API reference.",
+            ),
+            ResultCode::PAYMENT_AMOUNT_EXCEED_LIMIT => (
+                Decline,
+                "The payment amount exceeds the limit.",
+                "Lower the amount or ask the buyer to use another payment method.",
+            ),
+            ResultCode::PAYMENT_COUNT_EXCEED_LIMIT => (
+                Decline,
+                "The number of payments exceeds the limit.",
+                "Ask the buyer to use another payment method, or try again later.",
+            ),
+            ResultCode::PAYMENT_NOT_QUALIFIED => (
+                Misconfigured,
+                "The merchant cannot be paid because it is not registered or is restricted.",
+                "Contact Alipay Technical Support for detailed reasons.",
+            ),
+            ResultCode::PROCESS_FAIL => (
+                Decline,
+                "A general business failure occurred.",
+                "Do not retry. Contact Alipay Technical Support if it keeps happening.",
+            ),
+            ResultCode::REPEAT_REQ_INCONSISTENT => (
+                InvalidRequest,
+                "A request with the same request ID but different content was already sent.",
+                "Send the original content again, or use a new request ID.",
+            ),
+            ResultCode::RISK_REJECT => (
+                Decline,
+                "The request is rejected because of risk control.",
+                "Do not retry. Ask the buyer to use another payment method.",
+            ),
+            ResultCode::SETTLE_CONTRACT_NOT_MATCH => (
+                Misconfigured,
+                "No settlement contract matches the settlement currency.",
+                "Check the settlement currency, or contact Alipay Technical Support.",
+            ),
+            ResultCode::SYSTEM_ERROR => (
+                Retry,
+                "A system error occurred.",
+                "Retry with the same request ID.",
+            ),
+            ResultCode::USER_AMOUNT_EXCEED_LIMIT => (
+                Decline,
+                "The amount exceeds the limit of the buyer's account.",
+                "Ask the buyer to use another payment method.",
+            ),
+            ResultCode::USER_BALANCE_NOT_ENOUGH => (
+                Decline,
+                "The buyer's balance is not enough.",
+                "Ask the buyer to top up or use another payment method.",
+            ),
+            ResultCode::USER_KYC_NOT_QUALIFIED => (
+                Decline,
+                "The buyer is not qualified in the KYC check.",
+                "Ask the buyer to use another payment method.",
+            ),
+            ResultCode::PAYMENT_IN_PROCESS => (
+                Inquire,
+                "The payment is being processed.",
+                "Redirect the buyer if a URL was returned, then wait for the notification or inquire the payment.",
+            ),
+            ResultCode::REQUEST_TRAFFIC_EXCEED_LIMIT => (
+                Retry,
+                "The request traffic exceeds the limit.",
+                "Retry with the same request ID after a backoff.",
+            ),
+            ResultCode::UNKNOWN_EXCEPTION => (
+                Retry,
+                "The API call failed for an unknown reason. This is common in the sandbox.",
+                "Retry with the same request ID, or inquire the request.",
+            ),
+            ResultCode::USER_NOT_EXIST => (
+                Decline,
+                "The buyer account does not exist.",
+                "Ask the buyer to check the account or use another payment method.",
+            ),
+            ResultCode::ORDER_NOT_EXIST => (
+                Decline,
+                "The order does not exist.",
+                "Check the payment request ID. A payment that was never received can be created again.",
+            ),
+            ResultCode::ORDER_STATUS_INVALID => (
+                Decline,
+                "The order status is invalid for this operation.",
+                "Inquire the payment to check its status.",
+            ),
+            ResultCode::USER_PAYMENT_VERIFICATION_FAILED => (
+                Decline,
+                "The buyer failed the payment verification.",
+                "Ask the buyer to pay again.",
+            ),
+            ResultCode::USER_STATUS_ABNORMAL => (
+                Decline,
+                "The buyer's account status is abnormal.",
+                "Ask the buyer to use another payment method.",
+            ),
+            ResultCode::VERIFY_TIMES_EXCEED_LIMIT => (
+                Decline,
+                "The buyer exceeded the number of verification attempts.",
+                "Ask the buyer to try again later.",
+            ),
+            ResultCode::VERIFY_UNMATCHED => (
+                Decline,
+                "The verification code does not match.",
+                "Ask the buyer to enter the code again.",
+            ),
+            ResultCode::AUTHENTICATION_REQUIRED => (
+                Decline,
+                "3D Secure authentication is required.",
+                "Pay again with 3D Secure enabled.",
+            ),
+            ResultCode::SELECTED_CARD_BRAND_NOT_AVAILABLE => (
+                Decline,
+                "The selected card brand is not available.",
+                "Ask the buyer to use another card.",
+            ),
+            ResultCode::PAYMENT_PROHIBITED => (
+                Decline,
+                "The payment is prohibited.",
+                "Do not retry. Contact Alipay Technical Support for detailed reasons.",
+            ),
+            ResultCode::REFUND_AMOUNT_EXCEED => (
+                InvalidRequest,
+                "The total refund amount exceeds the payment amount.",
+                "Lower the refund amount.",
+            ),
+            ResultCode::REFUND_WINDOW_EXCEED => (
+                Decline,
+                "The refund window has passed.",
+                "Refund the buyer outside of Alipay.",
+            ),
+            ResultCode::REFUND_IN_PROCESS => (
+                Inquire,
+                "The refund is being processed.",
+                "Wait for the notification or inquire the refund.",
+            ),
+            ResultCode::REFUND_NOT_SUPPORTED => (
+                Decline,
+                "The payment method does not support refunds.",
+                "Refund the buyer outside of Alipay.",
+            ),
+            ResultCode::REFUND_NOT_EXIST => (
+                Decline,
+                "The refund does not exist.",
+                "Check the refund request ID. A refund that was never received can be sent again.",
+            ),
+            ResultCode::Other(_) => (
+                Inquire,
+                "A result code this version of the crate does not know.",
+                "Look the code up in Alipay's documentation, and inquire the request before acting on it.",
+            ),
+        }
+    }
+
+    /// How a result with this code should be handled
+    pub fn handling(&self) -> Handling {
+        self.guidance().0
+    }
+
+    /// What the code means
+    pub fn description(&self) -> &'static str {
+        self.guidance().1
+    }
+
+    /// What to do about it
+    pub fn recommended_action(&self) -> &'static str {
+        self.guidance().2
+    }
+
+    /// A transient failure, the same request may be sent again
+    pub fn is_retryable(&self) -> bool {
+        self.handling() == Handling::Retry
+    }
+
+    /// The outcome is not known yet and must be inquired
+    pub fn requires_inquiry(&self) -> bool {
+        self.handling() == Handling::Inquire
+    }
+
+    /// The outcome will not change, whatever it is
+    pub fn is_final(&self) -> bool {
+        !matches!(self.handling(), Handling::Retry | Handling::Inquire)
+    }
+
+    /// The request or the merchant configuration has to be fixed
+    pub fn is_programming_error(&self) -> bool {
+        matches!(
+            self.handling(),
+            Handling::InvalidRequest | Handling::Misconfigured
+        )
+    }
+}
+
+impl Error {
+    /// How this error should be handled.
+    ///
+    /// A request that got no verified response may still have been processed,
+    /// so it must be inquired.
+    pub fn handling(&self) -> Handling {
+        match self {
+            Error::Api { result_code, .. } => result_code.handling(),
+            Error::Transport {
+                status: Some(status),
+                ..
+            } if *status == 429 || *status >= 500 => Handling::Retry,
+            Error::Transport {
+                status: Some(_), ..
+            } => Handling::InvalidRequest,
+            // nothing was sent, the request can be signed and sent again
+            Error::Signature {
+                stage: SignatureStage::Sign,
+                ..
+            } => Handling::Retry,
+            Error::Transport { status: None, .. }
+            | Error::Signature {
+                stage: SignatureStage::Verify,
+                ..
+            }
+            | Error::Parse(_) => Handling::Inquire,
+            Error::Key(_) | Error::Config { .. } => Handling::Misconfigured,
+            // answer with a failed acknowledgement so Alipay delivers it again
+            Error::Webhook(WebhookError::Store(_)) => Handling::Retry,
+            Error::Webhook(_) => Handling::Decline,
+        }
+    }
+
+    /// A transient failure, the same request may be sent again
+    pub fn is_retryable(&self) -> bool {
+        self.handling() == Handling::Retry
+    }
+
+    /// The outcome is not known yet and must be inquired
+    pub fn requires_inquiry(&self) -> bool {
+        self.handling() == Handling::Inquire
+    }
+
+    /// The outcome will not change, whatever it is
+    pub fn is_final(&self) -> bool {
+        !matches!(self.handling(), Handling::Retry | Handling::Inquire)
+    }
+
+    /// The request or the merchant configuration has to be fixed
+    pub fn is_programming_error(&self) -> bool {
+        matches!(
+            self.handling(),
+            Handling::InvalidRequest | Handling::Misconfigured
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ResponseResult, ResultStatus};
+
+    #[test]
+    fn classifies_result_codes() {
+        assert!(ResultCode::SYSTEM_ERROR.is_retryable());
+        assert!(ResultCode::REQUEST_TRAFFIC_EXCEED_LIMIT.is_retryable());
+        assert!(ResultCode::PAYMENT_IN_PROCESS.requires_inquiry());
+        assert!(ResultCode::USER_BALANCE_NOT_ENOUGH.is_final());
+        assert!(!ResultCode::RISK_REJECT.is_programming_error());
+        assert!(ResultCode::PARAM_ILLEGAL.is_programming_error());
+        assert!(ResultCode::INVALID_API.is_programming_error());
+        assert!(!ResultCode::RISK_REJECT.recommended_action().is_empty());
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let api = Error::from(ResponseResult {
+            result_code: ResultCode::RISK_REJECT,
+            result_status: ResultStatus::F,
+            result_message: String::from("rejected"),
+        });
+        assert_eq!(api.handling(), Handling::Decline);
+        assert!(Error::transport("timed out").requires_inquiry());
+        assert!(Error::status(503, "").is_retryable());
+        assert!(Error::status(400, "").is_programming_error());
+        assert!(Error::config("missing key").is_final());
+    }
+
+    #[test]
+    fn retries_signing_but_inquires_unverified_responses() {
+        assert_eq!(Error::signing("agent down").handling(), Handling::Retry);
+        assert_eq!(
+            Error::signature("the signature does not match").handling(),
+            Handling::Inquire
+        );
+    }
+}
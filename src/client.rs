@@ -0,0 +1,543 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use super::endpoint::Endpoint;
+use super::errors::Error;
+use super::models::{
+    AlipayClientSecret, CashierPaymentFull, CashierPaymentInquiry, CashierPaymentRefundFull,
+    CashierPaymentRefundInquiry, CashierPaymentRefundSimple, CashierPaymentSimple, HasEndpoint,
+    Response, Signable, WebhookData, WebhookResponse, WebhookResponseInput,
+};
+use super::notification::Notification;
+use super::replay::WebhookPolicy;
+use super::resolve::{Outcome, Resolution, ResolvePolicy};
+use super::response::check_response;
+use super::retry::{settle, Attempts, RetryPolicy};
+use super::sansio::AlipayProtocol;
+use super::transport::{Transport, UreqTransport};
+
+/// A reusable Alipay Global client.
+///
+/// The client parses the merchant private key and the Alipay public key once,
+/// and keeps a pooled HTTP agent. It is `Send + Sync`, so build it at startup
+/// and share it (e.g. behind an `Arc`) across worker threads.
+///
+/// ```no_run
+/// use alipay_global::AlipayClient;
+/// use alipay_global::models::*;
+///
+/// let secret = AlipayClientSecret::new("SANDBOX_5Y...")
+///     .with_private_key_file("private_key.pem")
+///     .with_alipay_public_key_file("alipay_public_key.pem");
+/// let client = AlipayClient::new(&secret).unwrap();
+/// let inquiry = CashierPaymentInquiry {
+///     payment_request_id: Some(String::from("order-1")),
+///     payment_id: None,
+/// };
+/// let r = client.inquire(&inquiry);
+/// ```
+pub struct AlipayClient {
+    protocol: AlipayProtocol,
+    transport: Box<dyn Transport>,
+    retry_policy: RetryPolicy,
+}
+
+/// Default timeout applied to every request sent by the client's agent
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl AlipayClient {
+    /// Build a client from a secret, loading and parsing both keys once.
+    pub fn new(secret: &AlipayClientSecret) -> Result<Self, Error> {
+        let agent = ureq::AgentBuilder::new().timeout(DEFAULT_TIMEOUT).build();
+        Self::with_agent(secret, agent)
+    }
+
+    /// Build a client that sends requests through the given `ureq::Agent`.
+    pub fn with_agent(secret: &AlipayClientSecret, agent: ureq::Agent) -> Result<Self, Error> {
+        Self::with_transport(secret, UreqTransport::new(agent))
+    }
+
+    /// Build a client that sends requests through any [`Transport`],
+    /// e.g. an instrumented HTTP client or an in-memory fake.
+    pub fn with_transport(
+        secret: &AlipayClientSecret,
+        transport: impl Transport + 'static,
+    ) -> Result<Self, Error> {
+        Ok(Self::with_protocol(AlipayProtocol::new(secret)?, transport))
+    }
+
+    /// Build a client on a protocol whose keys were loaded some other way
+    pub fn with_protocol(protocol: AlipayProtocol, transport: impl Transport + 'static) -> Self {
+        Self {
+            protocol,
+            transport: Box::new(transport),
+            retry_policy: RetryPolicy::none(),
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        self.protocol.client_id()
+    }
+
+    pub fn is_sandbox(&self) -> bool {
+        self.protocol.is_sandbox()
+    }
+
+    /// Retry transient failures as described by `retry_policy`.
+    /// Requests are sent only once by default.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// How notifications are checked for freshness and replays.
+    /// Neither is checked by default, see [`WebhookPolicy::fresh`].
+    pub fn set_webhook_policy(&mut self, webhook_policy: WebhookPolicy) {
+        self.protocol.set_webhook_policy(webhook_policy);
+    }
+
+    /// The sans-IO layer this client signs and verifies with
+    pub fn protocol(&self) -> &AlipayProtocol {
+        &self.protocol
+    }
+
+    /// Create A [Cashier Payment](https://global.alipay.com/docs/ac/ams/payment_cashier)
+    pub fn pay(&self, cashier_payment: &CashierPaymentSimple) -> Result<Response, Error> {
+        self.execute(&CashierPaymentFull::from(cashier_payment))
+    }
+
+    /// [Inquire](https://global.alipay.com/docs/ac/ams/paymentri_online) the status of a payment
+    pub fn inquire(
+        &self,
+        cashier_payment_inquiry: &CashierPaymentInquiry,
+    ) -> Result<Response, Error> {
+        self.execute(cashier_payment_inquiry)
+    }
+
+    /// [Refund](https://global.alipay.com/docs/ac/ams/refund_online) a payment
+    pub fn refund(
+        &self,
+        cashier_payment_refund: &CashierPaymentRefundSimple,
+    ) -> Result<Response, Error> {
+        self.execute(&CashierPaymentRefundFull::from(cashier_payment_refund))
+    }
+
+    /// [Inquire](https://global.alipay.com/docs/ac/ams/ir_online) the status of a refund
+    pub fn inquire_refund(
+        &self,
+        cashier_payment_refund_inquiry: &CashierPaymentRefundInquiry,
+    ) -> Result<Response, Error> {
+        self.execute(cashier_payment_refund_inquiry)
+    }
+
+    /// Create a payment, and inquire it until its outcome is definitive
+    /// if the result is unknown or no response came back.
+    /// `PAYMENT_IN_PROCESS` is returned as [`Outcome::Processing`] without inquiring,
+    /// the buyer has to pay on the cashier page first.
+    pub fn pay_and_resolve(
+        &self,
+        cashier_payment: &CashierPaymentSimple,
+        resolve_policy: &ResolvePolicy,
+    ) -> Outcome {
+        self.resolve(Resolution::payment(cashier_payment, resolve_policy))
+    }
+
+    /// Refund a payment, and inquire the refund until its outcome is definitive
+    /// if the result is unknown or no response came back
+    pub fn refund_and_resolve(
+        &self,
+        cashier_payment_refund: &CashierPaymentRefundSimple,
+        resolve_policy: &ResolvePolicy,
+    ) -> Outcome {
+        self.resolve(Resolution::refund(cashier_payment_refund, resolve_policy))
+    }
+
+    fn resolve(&self, mut resolution: Resolution) -> Outcome {
+        loop {
+            let (endpoint, body) = resolution.request();
+            let sent = self.call_unchecked(endpoint, body);
+            match resolution.next(sent) {
+                ControlFlow::Break(outcome) => return outcome,
+                ControlFlow::Continue(wait) => std::thread::sleep(wait),
+            }
+        }
+    }
+
+    /// Verify a notification sent by Alipay and parse its body
+    pub fn verify_webhook(&self, webhook_data: &WebhookData) -> Result<Response, Error> {
+        self.protocol.verify_webhook(webhook_data)
+    }
+
+    /// Verify a notification sent by Alipay and parse it according to its type
+    pub fn verify_notification(&self, webhook_data: &WebhookData) -> Result<Notification, Error> {
+        self.protocol.verify_notification(webhook_data)
+    }
+
+    /// Forget a notification, so its next delivery is accepted, e.g. when handling it failed
+    pub fn forget_webhook(&self, webhook_data: &WebhookData) -> Result<(), Error> {
+        self.protocol.forget_webhook(webhook_data)
+    }
+
+    /// Build the signed acknowledgement telling Alipay the notification was handled
+    pub fn webhook_success_response(
+        &self,
+        webhook_response_in: WebhookResponseInput,
+    ) -> Result<WebhookResponse, Error> {
+        self.protocol.webhook_success_response(webhook_response_in)
+    }
+
+    /// Build the signed acknowledgement asking Alipay to deliver the notification again
+    pub fn webhook_failed_response(
+        &self,
+        webhook_response_in: WebhookResponseInput,
+    ) -> Result<WebhookResponse, Error> {
+        self.protocol.webhook_failed_response(webhook_response_in)
+    }
+
+    /// Call the API the request body belongs to
+    pub fn execute(&self, body: &impl HasEndpoint) -> Result<Response, Error> {
+        self.call(body.endpoint(), body)
+    }
+
+    /// Sign the request body, send it to `endpoint`, then verify and parse the response
+    pub fn call(&self, endpoint: Endpoint, body: &impl Signable) -> Result<Response, Error> {
+        check_response(self.call_unchecked(endpoint, body)?)
+    }
+
+    /// Like `call`, but a failed result is returned as a response, not an error
+    fn call_unchecked(&self, endpoint: Endpoint, body: &impl Signable) -> Result<Response, Error> {
+        let mut attempts = Attempts::new(&self.retry_policy);
+        loop {
+            let prepared = self.protocol.prepare_request(endpoint, body)?;
+            let path = prepared.path.clone();
+            let sent = self.transport.send(prepared.into());
+            match attempts.next(settle(&self.retry_policy, &self.protocol, &path, sent)) {
+                ControlFlow::Break(result) => return result,
+                ControlFlow::Continue(wait) => std::thread::sleep(wait),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::endpoint::Region;
+    use crate::models::HasPublicKey;
+    use crate::models::{load_private_key, HasPrivateKey};
+    use crate::signature::SignatureHeader;
+    use crate::signer::{RotatingSigner, RsaSigner, Signer};
+    use crate::test_support::{alipay_secret, fixture, merchant_secret, FakeAlipay};
+    use crate::transport::{HttpRequest, HttpResponse};
+
+    fn inquiry() -> CashierPaymentInquiry {
+        CashierPaymentInquiry {
+            payment_request_id: Some(String::from("order-1")),
+            payment_id: None,
+        }
+    }
+
+    #[test]
+    fn client_is_shareable_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AlipayClient>();
+    }
+
+    #[test]
+    fn sends_signed_request_through_transport() {
+        let fake = Arc::new(FakeAlipay::new(vec![json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+            "paymentStatus": "SUCCESS"
+        })]));
+        let client = AlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+
+        let response = client.inquire(&inquiry()).unwrap();
+        assert!(response.is_success());
+
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(
+            requests[0].url,
+            "https://open-global.alipay.com/ams/sandbox/api/v1/payments/inquiryPayment"
+        );
+        assert_eq!(requests[0].header("client-id"), Some("SANDBOX_TEST"));
+        assert!(requests[0].header("signature").is_some());
+        assert!(requests[0].header("request-time").is_some());
+    }
+
+    #[test]
+    fn targets_region_gateway_or_base_url_override() {
+        let success = json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+        });
+        let fake = Arc::new(FakeAlipay::new(vec![success.clone(), success]));
+
+        let mut secret = merchant_secret();
+        secret.region = Region::NorthAmerica;
+        let client = AlipayClient::with_transport(&secret, fake.clone()).unwrap();
+        client.inquire(&inquiry()).unwrap();
+
+        // the signed path includes the prefix of the override, like the server sees it
+        secret.base_url = Some(String::from("http://127.0.0.1:9000/stand-in/"));
+        let client = AlipayClient::with_transport(&secret, fake.clone()).unwrap();
+        client.inquire(&inquiry()).unwrap();
+
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(
+            requests[0].url,
+            "https://open-na-global.alipay.com/ams/sandbox/api/v1/payments/inquiryPayment"
+        );
+        assert_eq!(
+            requests[1].url,
+            "http://127.0.0.1:9000/stand-in/ams/sandbox/api/v1/payments/inquiryPayment"
+        );
+    }
+
+    #[test]
+    fn retries_transient_failures_with_the_same_body() {
+        let fake = Arc::new(FakeAlipay::with_results(vec![
+            Err(Error::transport("connection reset")),
+            Ok(json!({
+                "result": {"resultCode": "UNKNOWN_EXCEPTION", "resultStatus": "U", "resultMessage": "unknown"}
+            })),
+            Ok(json!({
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+            })),
+        ]));
+        let mut client = AlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+        client.set_retry_policy(RetryPolicy {
+            initial_backoff: std::time::Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+
+        assert!(client.inquire(&inquiry()).unwrap().is_success());
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.body == requests[0].body));
+    }
+
+    #[test]
+    fn does_not_retry_by_default_or_on_final_failures() {
+        let unknown = json!({
+            "result": {"resultCode": "UNKNOWN_EXCEPTION", "resultStatus": "U", "resultMessage": "unknown"}
+        });
+        let declined = json!({
+            "result": {"resultCode": "RISK_REJECT", "resultStatus": "F", "resultMessage": "rejected"}
+        });
+        let fake = Arc::new(FakeAlipay::new(vec![unknown, declined]));
+        let mut client = AlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+        assert!(client.inquire(&inquiry()).is_err());
+
+        client.set_retry_policy(RetryPolicy::default());
+        assert!(client.inquire(&inquiry()).is_err());
+        assert_eq!(fake.requests.lock().unwrap().len(), 2);
+    }
+
+    fn resolve_policy() -> ResolvePolicy {
+        ResolvePolicy {
+            timeout: Duration::from_secs(5),
+            initial_interval: Duration::from_millis(1),
+            ..ResolvePolicy::default()
+        }
+    }
+
+    fn cashier_payment() -> CashierPaymentSimple {
+        CashierPaymentSimple {
+            payment_request_id: String::from("order-1"),
+            currency: String::from("HKD"),
+            amount: 100,
+            redict_url: String::from("https://example.com/return"),
+            notifiy_url: String::from("https://example.com/notify"),
+            order_description: String::from("order"),
+            reference_order_id: None,
+            terminal_type: None,
+        }
+    }
+
+    #[test]
+    fn pay_and_resolve_inquires_until_the_status_is_final() {
+        let fake = Arc::new(FakeAlipay::with_results(vec![
+            Err(Error::transport("timed out")),
+            Ok(json!({
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+                "paymentStatus": "PROCESSING"
+            })),
+            Ok(json!({
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+                "paymentStatus": "SUCCESS"
+            })),
+        ]));
+        let client = AlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+
+        let outcome = client.pay_and_resolve(&cashier_payment(), &resolve_policy());
+        assert!(outcome.is_succeeded());
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].url.ends_with("/v1/payments/inquiryPayment"));
+        assert!(requests[1].body.contains(r#""paymentRequestId":"order-1""#));
+    }
+
+    #[test]
+    fn pay_and_resolve_inquires_after_a_gateway_timeout() {
+        let fake = Arc::new(FakeAlipay::with_results(vec![
+            Err(Error::status(504, "gateway timeout")),
+            Ok(json!({
+                "result": {"resultCode": "ORDER_NOT_EXIST", "resultStatus": "F", "resultMessage": "not exist"}
+            })),
+        ]));
+        let client = AlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+
+        let outcome = client.pay_and_resolve(&cashier_payment(), &resolve_policy());
+        assert!(matches!(outcome, Outcome::Failed(_)));
+        let requests = fake.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].url.ends_with("/v1/payments/inquiryPayment"));
+    }
+
+    #[test]
+    fn rotates_the_key_of_a_running_client() {
+        let secret = merchant_secret();
+        let mut rotating = RotatingSigner::new(RsaSigner::new(secret.get_private_key().unwrap()));
+        rotating.add(RsaSigner::with_key_version(
+            load_private_key(&fixture("alipay_private_key.pem")).unwrap(),
+            "2",
+        ));
+        let rotating = Arc::new(rotating);
+        let protocol = AlipayProtocol::with_signer(
+            &secret,
+            rotating.clone(),
+            secret.get_public_key().unwrap(),
+        )
+        .unwrap();
+        let success = json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+        });
+        let fake = Arc::new(FakeAlipay::new(vec![success.clone(), success]));
+        let client = AlipayClient::with_protocol(protocol, fake.clone());
+
+        client.inquire(&inquiry()).unwrap();
+        rotating.activate("2").unwrap();
+        client.inquire(&inquiry()).unwrap();
+
+        let requests = fake.requests.lock().unwrap();
+        let version = |i: usize| {
+            SignatureHeader::parse(requests[i].header("signature").unwrap())
+                .unwrap()
+                .key_version()
+                .map(String::from)
+        };
+        assert_eq!(version(0).as_deref(), Some("1"));
+        assert_eq!(version(1).as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn pay_and_resolve_does_not_inquire_when_signing_fails() {
+        struct Down;
+        impl Signer for Down {
+            fn sign_digest(&self, _: &[u8; 32]) -> Result<Vec<u8>, Error> {
+                Err(Error::signing("the signing agent is down"))
+            }
+        }
+        let secret = merchant_secret();
+        let protocol =
+            AlipayProtocol::with_signer(&secret, Down, secret.get_public_key().unwrap()).unwrap();
+        let fake = Arc::new(FakeAlipay::new(Vec::new()));
+        let client = AlipayClient::with_protocol(protocol, fake.clone());
+
+        let outcome = client.pay_and_resolve(&cashier_payment(), &resolve_policy());
+        assert!(matches!(outcome, Outcome::Error(Error::Signature { .. })));
+        assert!(fake.requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refund_and_resolve_gives_up_at_the_deadline() {
+        let processing = json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+            "refundStatus": "PROCESSING"
+        });
+        let fake = Arc::new(FakeAlipay::new(vec![
+            json!({
+                "result": {"resultCode": "REFUND_IN_PROCESS", "resultStatus": "U", "resultMessage": "in process"}
+            }),
+            processing.clone(),
+            processing.clone(),
+            processing,
+        ]));
+        let client = AlipayClient::with_transport(&merchant_secret(), fake.clone()).unwrap();
+        let refund = CashierPaymentRefundSimple {
+            refund_request_id: String::from("refund-1"),
+            payment_id: String::from("payment-1"),
+            amount: 100,
+            currency: String::from("HKD"),
+        };
+        let policy = ResolvePolicy {
+            timeout: Duration::from_millis(35),
+            initial_interval: Duration::from_millis(15),
+            multiplier: 1.0,
+            ..ResolvePolicy::default()
+        };
+
+        let outcome = client.refund_and_resolve(&refund, &policy);
+        assert!(matches!(outcome, Outcome::Processing(_)));
+        assert!(fake.requests.lock().unwrap().len() <= 4);
+    }
+
+    #[test]
+    fn rejects_response_not_signed_by_alipay() {
+        struct Unsigned;
+        impl Transport for Unsigned {
+            fn send(&self, _: HttpRequest) -> Result<HttpResponse, Error> {
+                Ok(HttpResponse {
+                    status: 200,
+                    headers: vec![
+                        (
+                            String::from("signature"),
+                            String::from("algorithm=RSA256,keyVersion=1,signature=AAAA"),
+                        ),
+                        (
+                            String::from("response-time"),
+                            String::from("2023-01-01T00:00:00+08:00"),
+                        ),
+                        (String::from("client-id"), String::from("SANDBOX_TEST")),
+                    ],
+                    body: String::from(
+                        r#"{"result":{"resultCode":"SUCCESS","resultStatus":"S","resultMessage":"success"}}"#,
+                    ),
+                })
+            }
+        }
+        let client = AlipayClient::with_transport(&merchant_secret(), Unsigned).unwrap();
+        assert!(matches!(
+            client.inquire(&inquiry()),
+            Err(Error::Signature { .. })
+        ));
+    }
+
+    #[test]
+    fn verifies_webhook_signed_by_alipay() {
+        let merchant = AlipayClient::new(&merchant_secret()).unwrap();
+        // plays the Alipay side: signs with the alipay key
+        let alipay = AlipayClient::new(&alipay_secret()).unwrap();
+        let notification = alipay
+            .webhook_success_response(WebhookResponseInput {
+                method: String::from("POST"),
+                path: String::from("/notify"),
+                client_id: String::from("SANDBOX_TEST"),
+            })
+            .unwrap();
+        let mut webhook_data = WebhookData {
+            method: String::from("POST"),
+            path: String::from("/notify"),
+            request_time: notification.response_time,
+            header_signature: notification.full_signature,
+            client_id: notification.client_id,
+            request_body: notification.body,
+        };
+        assert!(merchant.verify_webhook(&webhook_data).unwrap().is_success());
+
+        webhook_data.path = String::from("/other");
+        assert!(merchant.verify_webhook(&webhook_data).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Every Alipay Global (AMS) API the crate knows how to reach.
+///
+/// An endpoint only knows its API path, the sandbox or live prefix is added by
+/// [`Endpoint::path`], so the same credentials can be used for every operation.
+/// see: https://global.alipay.com/docs/ac/ams/api
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    // Payments
+    Pay,
+    Consult,
+    CreatePaymentSession,
+    Capture,
+    InquiryPayment,
+    Cancel,
+    Refund,
+    InquiryRefund,
+    InitAuthentication,
+    VerifyAuthentication,
+    // Authorizations (auto debit)
+    AuthorizationConsult,
+    ApplyToken,
+    RevokeToken,
+    // Subscriptions
+    CreateSubscription,
+    ChangeSubscription,
+    CancelSubscription,
+    UpdateSubscription,
+    // Customs
+    Declare,
+    InquiryDeclarationRequests,
+    // Disputes
+    AcceptDispute,
+    SupplyDefenseDocument,
+    DownloadDisputeEvidence,
+    // Vaulting
+    CreateVaultingSession,
+    VaultPaymentMethod,
+    InquireVaulting,
+    // Marketplace
+    Register,
+    UpdateSettlementInfo,
+    InquireBalance,
+    Settle,
+    CreatePayout,
+    CreateTransfer,
+}
+
+const LIVE_PREFIX: &str = "/ams/api";
+const SANDBOX_PREFIX: &str = "/ams/sandbox/api";
+
+impl Endpoint {
+    /// Every known endpoint, in declaration order
+    pub const ALL: [Endpoint; 31] = [
+        Endpoint::Pay,
+        Endpoint::Consult,
+        Endpoint::CreatePaymentSession,
+        Endpoint::Capture,
+        Endpoint::InquiryPayment,
+        Endpoint::Cancel,
+        Endpoint::Refund,
+        Endpoint::InquiryRefund,
+        Endpoint::InitAuthentication,
+        Endpoint::VerifyAuthentication,
+        Endpoint::AuthorizationConsult,
+        Endpoint::ApplyToken,
+        Endpoint::RevokeToken,
+        Endpoint::CreateSubscription,
+        Endpoint::ChangeSubscription,
+        Endpoint::CancelSubscription,
+        Endpoint::UpdateSubscription,
+        Endpoint::Declare,
+        Endpoint::InquiryDeclarationRequests,
+        Endpoint::AcceptDispute,
+        Endpoint::SupplyDefenseDocument,
+        Endpoint::DownloadDisputeEvidence,
+        Endpoint::CreateVaultingSession,
+        Endpoint::VaultPaymentMethod,
+        Endpoint::InquireVaulting,
+        Endpoint::Register,
+        Endpoint::UpdateSettlementInfo,
+        Endpoint::InquireBalance,
+        Endpoint::Settle,
+        Endpoint::CreatePayout,
+        Endpoint::CreateTransfer,
+    ];
+
+    /// The versioned API path without the environment prefix, e.g. `/v1/payments/pay`
+    pub fn api_path(&self) -> &'static str {
+        match self {
+            Endpoint::Pay => "/v1/payments/pay",
+            Endpoint::Consult => "/v1/payments/consult",
+            Endpoint::CreatePaymentSession => "/v1/payments/createPaymentSession",
+            Endpoint::Capture => "/v1/payments/capture",
+            Endpoint::InquiryPayment => "/v1/payments/inquiryPayment",
+            Endpoint::Cancel => "/v1/payments/cancel",
+            Endpoint::Refund => "/v1/payments/refund",
+            Endpoint::InquiryRefund => "/v1/payments/inquiryRefund",
+            Endpoint::InitAuthentication => "/v1/payments/initAuthentication",
+            Endpoint::VerifyAuthentication => "/v1/payments/verifyAuthentication",
+            Endpoint::AuthorizationConsult => "/v1/authorizations/consult",
+            Endpoint::ApplyToken => "/v1/authorizations/applyToken",
+            Endpoint::RevokeToken => "/v1/authorizations/revoke",
+            Endpoint::CreateSubscription => "/v1/subscriptions/create",
+            Endpoint::ChangeSubscription => "/v1/subscriptions/change",
+            Endpoint::CancelSubscription => "/v1/subscriptions/cancel",
+            Endpoint::UpdateSubscription => "/v1/subscriptions/update",
+            Endpoint::Declare => "/v1/customs/declare",
+            Endpoint::InquiryDeclarationRequests => "/v1/customs/inquiryDeclarationRequests",
+            Endpoint::AcceptDispute => "/v1/payments/acceptDispute",
+            Endpoint::SupplyDefenseDocument => "/v1/payments/supplyDefenseDocument",
+            Endpoint::DownloadDisputeEvidence => "/v1/payments/downloadDisputeEvidence",
+            Endpoint::CreateVaultingSession => "/v1/vaults/createVaultingSession",
+            Endpoint::VaultPaymentMethod => "/v1/vaults/vaultPaymentMethod",
+            Endpoint::InquireVaulting => "/v1/vaults/inquireVaulting",
+            Endpoint::Register => "/v1/marketplaces/register",
+            Endpoint::UpdateSettlementInfo => "/v1/marketplaces/settlementInfo/update",
+            Endpoint::InquireBalance => "/v1/marketplaces/inquireBalance",
+            Endpoint::Settle => "/v1/marketplaces/settle",
+            Endpoint::CreatePayout => "/v1/funds/createPayout",
+            Endpoint::CreateTransfer => "/v1/funds/createTransfer",
+        }
+    }
+
+    /// The full path used both for signing and for the request URL
+    pub fn path(&self, sandbox: bool) -> String {
+        let prefix = if sandbox { SANDBOX_PREFIX } else { LIVE_PREFIX };
+        format!("{}{}", prefix, self.api_path())
+    }
+
+    /// Look up an endpoint by the path Alipay documents, with or without the environment prefix
+    pub fn from_path(path: &str) -> Option<Endpoint> {
+        let api_path = path
+            .strip_prefix(SANDBOX_PREFIX)
+            .or_else(|| path.strip_prefix(LIVE_PREFIX))
+            .unwrap_or(path);
+        Endpoint::ALL
+            .into_iter()
+            .find(|endpoint| endpoint.api_path() == api_path)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.api_path())
+    }
+}
+
+/// The Alipay Global gateway a merchant was onboarded on.
+/// see: https://global.alipay.com/docs/ac/ams/api_fund
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Region {
+    #[default]
+    Global,
+    NorthAmerica,
+    SoutheastAsia,
+    Europe,
+}
+
+impl Region {
+    /// The gateway domain requests are sent to
+    pub fn domain(&self) -> &'static str {
+        match self {
+            Region::Global => "https://open-global.alipay.com",
+            Region::NorthAmerica => "https://open-na-global.alipay.com",
+            Region::SoutheastAsia => "https://open-sea-global.alipay.com",
+            Region::Europe => "https://open-de-global.alipay.com",
+        }
+    }
+}
+
+/// The prefix Alipay gives the client ids of sandbox accounts
+pub const SANDBOX_CLIENT_ID_PREFIX: &str = "SANDBOX_";
+
+/// Whether requests reach the sandbox or move real money
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Environment {
+    Sandbox,
+    Live,
+}
+
+impl Environment {
+    pub fn from_sandbox(sandbox: bool) -> Self {
+        if sandbox {
+            Environment::Sandbox
+        } else {
+            Environment::Live
+        }
+    }
+
+    /// The environment a client id was issued for, sandbox ids start with `SANDBOX_`
+    pub fn of_client_id(client_id: &str) -> Self {
+        Self::from_sandbox(client_id.starts_with(SANDBOX_CLIENT_ID_PREFIX))
+    }
+
+    pub fn is_sandbox(&self) -> bool {
+        matches!(self, Environment::Sandbox)
+    }
+
+    pub fn other(&self) -> Self {
+        match self {
+            Environment::Sandbox => Environment::Live,
+            Environment::Live => Environment::Sandbox,
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Environment::Sandbox => f.write_str("sandbox"),
+            Environment::Live => f.write_str("live"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_environment() {
+        assert_eq!(Endpoint::Pay.path(true), "/ams/sandbox/api/v1/payments/pay");
+        assert_eq!(
+            Endpoint::InquiryPayment.path(false),
+            "/ams/api/v1/payments/inquiryPayment"
+        );
+    }
+
+    #[test]
+    fn paths_are_unique_and_round_trip() {
+        for endpoint in Endpoint::ALL {
+            assert_eq!(Endpoint::from_path(&endpoint.path(true)), Some(endpoint));
+            assert_eq!(Endpoint::from_path(&endpoint.path(false)), Some(endpoint));
+        }
+        assert_eq!(Endpoint::from_path("/v1/payments/unknown"), None);
+    }
+
+    #[test]
+    fn client_ids_tell_their_environment() {
+        assert_eq!(
+            Environment::of_client_id("SANDBOX_5Y3A2N2YEB3002022"),
+            Environment::Sandbox
+        );
+        assert_eq!(
+            Environment::of_client_id("5Y3A2N2YEB3002022"),
+            Environment::Live
+        );
+    }
+}
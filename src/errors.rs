@@ -1,32 +1,473 @@
-use std::fmt::format;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use serde::Serialize;
+use serde::ser::{Serialize, SerializeStructVariant, Serializer};
 
-/// Custom Error for Alipay Response
-/// Fail: Indicates that the API call fails.
-/// Unknown: Indicates that the API call might be successful, in process, or failed. For more details, see Result process logic.
-#[derive(Debug, Serialize)]
+use super::models::{ResponseResult, ResultCode, ResultStatus};
+
+/// The error type a source error is kept as
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Everything that can go wrong talking to Alipay Global
+#[derive(Debug)]
 pub enum Error {
-    Fail(String),
-    Unknown(String),
+    /// Alipay answered, but the result is not a success.
+    /// A `U` status means the call might have succeeded, see Result process logic.
+    Api {
+        result_code: ResultCode,
+        result_status: ResultStatus,
+        result_message: String,
+    },
+    /// No usable HTTP response: the request could not be sent, no response came back,
+    /// or the status is not 2xx. `status` and `body` are set when a response came back.
+    Transport {
+        status: Option<u16>,
+        body: String,
+        source: Option<BoxError>,
+    },
+    /// A signature could not be created, or Alipay's signature is missing or does not match
+    Signature {
+        stage: SignatureStage,
+        reason: String,
+        source: Option<BoxError>,
+    },
+    /// A body is not the JSON Alipay is expected to send
+    Parse(serde_json::Error),
+    /// A key is missing or cannot be loaded
+    Key(KeyError),
+    /// The client is misconfigured
+    Config {
+        reason: String,
+        source: Option<BoxError>,
+    },
+    /// A notification is correctly signed but refused
+    Webhook(WebhookError),
+}
+
+/// Whether a signature failed to be made or to be verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStage {
+    /// Signing a request or an acknowledgement failed, nothing was sent to Alipay
+    Sign,
+    /// The signature of a response or a notification is missing, malformed or does not match
+    Verify,
+}
+
+/// The keys a client needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// The merchant private key requests and acknowledgements are signed with
+    MerchantPrivate,
+    /// The Alipay public key responses and notifications are verified with
+    AlipayPublic,
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::MerchantPrivate => write!(f, "merchant private key"),
+            Key::AlipayPublic => write!(f, "alipay public key"),
+        }
+    }
+}
+
+/// Why a key could not be loaded
+#[derive(Debug)]
+pub enum KeyError {
+    /// Neither the PEM nor the PEM file of the key is set
+    Missing(Key),
+    /// The key file cannot be read
+    Read {
+        key: Key,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The environment variable holding the key is not set or not unicode
+    Env {
+        key: Key,
+        name: String,
+        source: std::env::VarError,
+    },
+    /// The key is encrypted but no passphrase was given
+    PassphraseRequired(Key),
+    /// The content is not a valid RSA key
+    Invalid { key: Key, reason: String },
+}
+
+impl KeyError {
+    /// The key that failed to load
+    pub fn key(&self) -> Key {
+        match self {
+            KeyError::Missing(key)
+            | KeyError::PassphraseRequired(key)
+            | KeyError::Read { key, .. }
+            | KeyError::Env { key, .. }
+            | KeyError::Invalid { key, .. } => *key,
+        }
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Missing(key) => write!(f, "the {} is not configured", key),
+            KeyError::Read { key, path, source } => {
+                write!(
+                    f,
+                    "cannot read the {} from {}: {}",
+                    key,
+                    path.display(),
+                    source
+                )
+            }
+            KeyError::PassphraseRequired(key) => {
+                write!(f, "the {} is encrypted, a passphrase is required", key)
+            }
+            KeyError::Env { key, name, source } => {
+                write!(f, "cannot read the {} from ${}: {}", key, name, source)
+            }
+            KeyError::Invalid { key, reason } => write!(f, "the {} is invalid: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyError::Read { source, .. } => Some(source),
+            KeyError::Env { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Why a correctly signed notification is refused
+#[derive(Debug)]
+pub enum WebhookError {
+    /// The `Request-Time` is not an RFC 3339 time
+    InvalidTime(String),
+    /// The `Request-Time` is further from now than the allowed clock skew
+    Stale {
+        request_time: String,
+        max_clock_skew: Duration,
+    },
+    /// The notification was received before, under this replay key
+    Replayed(String),
+    /// The replay store cannot be read or written
+    Store(BoxError),
+    /// A header a notification is verified with is not given
+    MissingHeader(&'static str),
+    /// A header is given more than once, is not text or has a malformed value
+    InvalidHeader { name: &'static str, reason: String },
+    /// The body is not UTF-8
+    InvalidBody(std::str::Utf8Error),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebhookError::InvalidTime(time) => write!(f, "invalid request time {}", time),
+            WebhookError::Stale {
+                request_time,
+                max_clock_skew,
+            } => write!(
+                f,
+                "the notification was sent at {}, more than {}s from now",
+                request_time,
+                max_clock_skew.as_secs()
+            ),
+            WebhookError::Replayed(key) => {
+                write!(f, "the notification {} was already received", key)
+            }
+            WebhookError::Store(e) => write!(f, "cannot record the notification: {}", e),
+            WebhookError::MissingHeader(name) => write!(f, "missing the {} header", name),
+            WebhookError::InvalidHeader { name, reason } => {
+                write!(f, "invalid {} header: {}", name, reason)
+            }
+            WebhookError::InvalidBody(_) => write!(f, "the body is not UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebhookError::Store(e) => Some(e.as_ref()),
+            WebhookError::InvalidBody(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// A request that got no response at all, for [`Transport`](crate::transport::Transport) implementations
+    pub fn transport(source: impl Into<BoxError>) -> Self {
+        Self::Transport {
+            status: None,
+            body: String::new(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// A response with a non 2xx status
+    pub fn status(status: u16, body: impl Into<String>) -> Self {
+        Self::Transport {
+            status: Some(status),
+            body: body.into(),
+            source: None,
+        }
+    }
+
+    /// A signature that cannot be made, for [`Signer`](crate::signer::Signer) implementations
+    pub fn signing(reason: impl Into<String>) -> Self {
+        Self::Signature {
+            stage: SignatureStage::Sign,
+            reason: reason.into(),
+            source: None,
+        }
+    }
+
+    /// A signing error caused by `source`
+    pub fn signing_caused_by(reason: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::Signature {
+            stage: SignatureStage::Sign,
+            reason: reason.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// A signature from Alipay that is missing, malformed or does not match
+    pub fn signature(reason: impl Into<String>) -> Self {
+        Self::Signature {
+            stage: SignatureStage::Verify,
+            reason: reason.into(),
+            source: None,
+        }
+    }
+
+    /// A verification error caused by `source`
+    pub fn signature_caused_by(reason: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::Signature {
+            stage: SignatureStage::Verify,
+            reason: reason.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// A setting that cannot work, e.g. an invalid base URL
+    pub fn config(reason: impl Into<String>) -> Self {
+        Self::Config {
+            reason: reason.into(),
+            source: None,
+        }
+    }
+
+    /// A configuration error caused by `source`
+    pub fn config_caused_by(reason: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::Config {
+            reason: reason.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    /// The result code of an API error
+    pub fn result_code(&self) -> Option<&ResultCode> {
+        match self {
+            Self::Api { result_code, .. } => Some(result_code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Api {
+                result_code,
+                result_status,
+                result_message,
+            } => write!(
+                f,
+                "alipay returned {} ({}): {}",
+                result_code, result_status, result_message
+            ),
+            Self::Transport {
+                status: Some(status),
+                body,
+                ..
+            } => write!(f, "request failed with status {}: {}", status, body),
+            Self::Transport { status: None, .. } => write!(f, "request failed"),
+            Self::Signature {
+                stage: SignatureStage::Sign,
+                reason,
+                ..
+            } => write!(f, "cannot sign: {}", reason),
+            Self::Signature {
+                stage: SignatureStage::Verify,
+                reason,
+                ..
+            } => write!(f, "signature error: {}", reason),
+            Self::Parse(_) => write!(f, "failed to parse the body"),
+            Self::Key(e) => write!(f, "{}", e),
+            Self::Config { reason, .. } => write!(f, "invalid configuration: {}", reason),
+            Self::Webhook(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Serialized as the variant and its message, e.g. `{"Transport":"request failed"}`,
+/// an API error as its result fields
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (index, variant) = match self {
+            Self::Api {
+                result_code,
+                result_status,
+                result_message,
+            } => {
+                let mut api = serializer.serialize_struct_variant("Error", 0, "Api", 3)?;
+                api.serialize_field("result_code", result_code)?;
+                api.serialize_field("result_status", result_status)?;
+                api.serialize_field("result_message", result_message)?;
+                return api.end();
+            }
+            Self::Transport { .. } => (1, "Transport"),
+            Self::Signature { .. } => (2, "Signature"),
+            Self::Parse(_) => (3, "Parse"),
+            Self::Key(_) => (4, "Key"),
+            Self::Config { .. } => (5, "Config"),
+            Self::Webhook(_) => (6, "Webhook"),
+        };
+        serializer.serialize_newtype_variant("Error", index, variant, &self.to_string())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport { source, .. }
+            | Self::Signature { source, .. }
+            | Self::Config { source, .. } => source
+                .as_deref()
+                .map(|e| e as &(dyn std::error::Error + 'static)),
+            Self::Parse(e) => Some(e),
+            // a key error is displayed in full, its own source comes next
+            Self::Key(e) => e.source(),
+            Self::Webhook(e) => e.source(),
+            Self::Api { .. } => None,
+        }
+    }
+}
+
+impl From<ResponseResult> for Error {
+    fn from(value: ResponseResult) -> Self {
+        Self::Api {
+            result_code: value.result_code,
+            result_status: value.result_status,
+            result_message: value.result_message,
+        }
+    }
 }
 
 impl From<ureq::Error> for Error {
     fn from(value: ureq::Error) -> Self {
         match value {
-            ureq::Error::Status(x, res) => Self::Fail(format!(
-                "Request Status code {}, {}",
-                x.to_string(),
-                res.into_string().unwrap()
-            )),
-            ureq::Error::Transport(t) => {
-                Self::Fail(format!("Request transport error: {}", t.to_string()))
+            ureq::Error::Status(status, res) => {
+                Self::status(status, res.into_string().unwrap_or_default())
             }
+            ureq::Error::Transport(t) => Self::transport(t),
         }
     }
 }
+
+#[cfg(feature = "async")]
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Transport {
+            status: value.status().map(|s| s.as_u16()),
+            body: String::new(),
+            source: Some(Box::new(value)),
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
-        Self::Unknown(format!("Std IO Error: {}", value.to_string()))
+        Self::transport(value)
+    }
+}
+
+impl From<KeyError> for Error {
+    fn from(value: KeyError) -> Self {
+        Self::Key(value)
+    }
+}
+
+impl From<WebhookError> for Error {
+    fn from(value: WebhookError) -> Self {
+        Self::Webhook(value)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Parse(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+
+    #[test]
+    fn api_errors_keep_the_result_message() {
+        let e = Error::from(ResponseResult {
+            result_code: ResultCode::RISK_REJECT,
+            result_status: ResultStatus::F,
+            result_message: String::from("rejected by risk control"),
+        });
+        assert_eq!(e.result_code(), Some(&ResultCode::RISK_REJECT));
+        assert_eq!(
+            e.to_string(),
+            "alipay returned RISK_REJECT (F): rejected by risk control"
+        );
+    }
+
+    #[test]
+    fn chains_the_source() {
+        let parse = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let e = Error::from(parse);
+        assert!(e.source().is_some());
+
+        let e = Error::transport("connection reset");
+        assert_eq!(e.source().unwrap().to_string(), "connection reset");
+        assert_eq!(
+            Error::status(502, "bad gateway").to_string(),
+            "request failed with status 502: bad gateway"
+        );
+    }
+
+    #[test]
+    fn serializes_the_message() {
+        assert_eq!(
+            serde_json::to_value(Error::config("missing key")).unwrap(),
+            serde_json::json!({"Config": "invalid configuration: missing key"})
+        );
+        let api = Error::from(ResponseResult {
+            result_code: ResultCode::SYSTEM_ERROR,
+            result_status: ResultStatus::U,
+            result_message: String::from("try again"),
+        });
+        assert_eq!(
+            serde_json::to_value(api).unwrap(),
+            serde_json::json!({"Api": {
+                "result_code": "SYSTEM_ERROR",
+                "result_status": "U",
+                "result_message": "try again"
+            }})
+        );
     }
 }
@@ -0,0 +1,529 @@
+//! Receive Alipay notifications in an axum application.
+//!
+//! Take a [`VerifiedNotification`] in a handler and answer with its signed
+//! acknowledgement, or wrap the route in an [`AlipayWebhookLayer`], which verifies
+//! each request and turns the handler's status into the acknowledgement. Both
+//! verify and acknowledge like [`cashier_payment`](crate::webhook::cashier_payment),
+//! [`success_response`](crate::webhook::success_response) and
+//! [`failed_response`](crate::webhook::failed_response), with the keys loaded once
+//! instead of for every notification.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use ::axum::body::{to_bytes, Body, Bytes};
+use ::axum::extract::{FromRef, FromRequest, OriginalUri, Request};
+use ::axum::http::StatusCode;
+use ::axum::response::{IntoResponse, Response};
+use serde_json::Value;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::blocking::unblock;
+use crate::errors::{Error, WebhookError};
+use crate::models::{AlipayClientSecret, WebhookData, WebhookResponseInput};
+use crate::notification::Notification;
+use crate::sansio::AlipayProtocol;
+
+/// The largest notification body read
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Verifies notifications and signs their acknowledgements, shared as axum state
+///
+/// ```no_run
+/// use alipay_global::{AlipayWebhook, VerifiedNotification};
+/// use alipay_global::notification::Notification;
+/// use axum::response::Response;
+/// use axum::routing::post;
+/// use axum::Router;
+///
+/// async fn notify(notification: VerifiedNotification) -> Response {
+///     match notification.notification() {
+///         Notification::Payment(payment) => println!("{} is paid", payment.payment_request_id),
+///         _ => {}
+///     }
+///     notification.success().await
+/// }
+///
+/// # fn run(secret: alipay_global::models::AlipayClientSecret) -> Result<(), alipay_global::errors::Error> {
+/// let app: Router = Router::new()
+///     .route("/alipay/notify", post(notify))
+///     .with_state(AlipayWebhook::new(&secret)?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct AlipayWebhook {
+    protocol: Arc<AlipayProtocol>,
+}
+
+impl AlipayWebhook {
+    /// Load the keys of `secret`, notifications are checked with the default policy
+    pub fn new(secret: &AlipayClientSecret) -> Result<Self, Error> {
+        Ok(AlipayProtocol::new(secret)?.into())
+    }
+
+    pub fn protocol(&self) -> &AlipayProtocol {
+        &self.protocol
+    }
+
+    /// Read a request of at most [`MAX_BODY`] bytes and verify it.
+    /// The path signed by Alipay is the one before any `Router::nest` stripped a prefix.
+    /// A replayed notification is answered as handled right away.
+    async fn verify(
+        &self,
+        request: Request,
+    ) -> Result<(Request<Bytes>, Pending, Notification), Response> {
+        let (parts, body) = request.into_parts();
+        let body = to_bytes(body, MAX_BODY)
+            .await
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+        let request = Request::from_parts(parts, body);
+        let mut data = WebhookData::from_http_request(&request).map_err(reject)?;
+        if let Some(OriginalUri(uri)) = request.extensions().get::<OriginalUri>() {
+            data.path = uri.path().to_string();
+        }
+        // the guard is made as soon as the notification is recorded, so it is forgotten
+        // even if this future is dropped before verifying finishes
+        let webhook = self.clone();
+        let verified = unblock(&self.protocol, move |protocol| {
+            Ok(match protocol.verify_notification(&data) {
+                Ok(notification) => Ok((Pending::new(webhook, data), notification)),
+                Err(e) => Err((data, e)),
+            })
+        })
+        .await
+        .map_err(reject)?;
+        match verified {
+            Ok((pending, notification)) => Ok((request, pending, notification)),
+            Err((data, Error::Webhook(WebhookError::Replayed(_)))) => {
+                Err(self.acknowledge(&data, true).await)
+            }
+            Err((_, e)) => Err(reject(e)),
+        }
+    }
+
+    /// The signed acknowledgement, forgetting the notification when it was not handled
+    async fn acknowledge(&self, data: &WebhookData, handled: bool) -> Response {
+        let input = WebhookResponseInput {
+            method: data.method.clone(),
+            path: data.path.clone(),
+            client_id: data.client_id.clone(),
+        };
+        let data = data.clone();
+        let acknowledgement = unblock(&self.protocol, move |protocol| {
+            if handled {
+                protocol.webhook_success_response(input)
+            } else {
+                // Alipay is asked to deliver again either way
+                let _ = protocol.forget_webhook(&data);
+                protocol.webhook_failed_response(input)
+            }
+        })
+        .await;
+        match acknowledgement.and_then(::http::Response::<Bytes>::try_from) {
+            Ok(response) => response.map(Body::from),
+            Err(e) => reject(e),
+        }
+    }
+}
+
+impl From<AlipayProtocol> for AlipayWebhook {
+    fn from(protocol: AlipayProtocol) -> Self {
+        Self {
+            protocol: Arc::new(protocol),
+        }
+    }
+}
+
+/// A recorded notification, forgotten when dropped unless it was acknowledged as handled,
+/// so that Alipay's next delivery is not answered as a replay
+struct Pending {
+    webhook: AlipayWebhook,
+    data: WebhookData,
+    handled: AtomicBool,
+}
+
+impl Pending {
+    fn new(webhook: AlipayWebhook, data: WebhookData) -> Self {
+        Self {
+            webhook,
+            data,
+            handled: AtomicBool::new(false),
+        }
+    }
+
+    async fn acknowledge(&self, handled: bool) -> Response {
+        let response = self.webhook.acknowledge(&self.data, handled).await;
+        if handled && response.status().is_success() {
+            self.handled.store(true, Ordering::Relaxed);
+        }
+        response
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if self.handled.load(Ordering::Relaxed) {
+            return;
+        }
+        let protocol = self.webhook.protocol.clone();
+        let data = self.data.clone();
+        // nothing is left to report the error to, Alipay delivers again either way
+        let forget = move || {
+            let _ = protocol.forget_webhook(&data);
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(forget)),
+            Err(_) => forget(),
+        }
+    }
+}
+
+/// A notification whose signature was verified, rejecting any other request.
+///
+/// Unless the handler answers with [`success`](Self::success), the notification is
+/// forgotten when this is dropped, so Alipay's next delivery reaches the handler again.
+pub struct VerifiedNotification {
+    pending: Pending,
+    notification: Notification,
+}
+
+impl VerifiedNotification {
+    pub fn notification(&self) -> &Notification {
+        &self.notification
+    }
+
+    pub fn data(&self) -> &WebhookData {
+        &self.pending.data
+    }
+
+    /// Take the notification, which can no longer be acknowledged as handled
+    pub fn into_notification(mut self) -> Notification {
+        std::mem::replace(&mut self.notification, Notification::Other(Value::Null))
+    }
+
+    /// The signed acknowledgement telling Alipay the notification was handled
+    pub async fn success(&self) -> Response {
+        self.pending.acknowledge(true).await
+    }
+
+    /// The signed acknowledgement asking Alipay to deliver the notification again
+    pub async fn failed(&self) -> Response {
+        self.pending.acknowledge(false).await
+    }
+}
+
+impl<S> FromRequest<S> for VerifiedNotification
+where
+    AlipayWebhook: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let webhook = AlipayWebhook::from_ref(state);
+        let (_, pending, notification) = webhook.verify(request).await?;
+        Ok(Self {
+            pending,
+            notification,
+        })
+    }
+}
+
+/// Verifies the notifications sent to a route and signs the acknowledgement.
+///
+/// The route gets the [`Notification`] as an extension. A success status from it
+/// is acknowledged as handled, any other status asks Alipay to deliver again.
+#[derive(Clone)]
+pub struct AlipayWebhookLayer {
+    webhook: AlipayWebhook,
+}
+
+impl AlipayWebhookLayer {
+    pub fn new(webhook: AlipayWebhook) -> Self {
+        Self { webhook }
+    }
+}
+
+impl<S> Layer<S> for AlipayWebhookLayer {
+    type Service = AlipayWebhookService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AlipayWebhookService {
+            webhook: self.webhook.clone(),
+            inner,
+        }
+    }
+}
+
+/// The service an [`AlipayWebhookLayer`] wraps a route in
+#[derive(Clone)]
+pub struct AlipayWebhookService<S> {
+    webhook: AlipayWebhook,
+    inner: S,
+}
+
+impl<S> Service<Request> for AlipayWebhookService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let webhook = self.webhook.clone();
+        // call the inner service that was polled ready, leaving a clone in its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let (request, pending, notification) = match webhook.verify(request).await {
+                Ok(verified) => verified,
+                Err(response) => return Ok(response),
+            };
+            let mut request = request.map(Body::from);
+            request.extensions_mut().insert(notification);
+            // if the route panics or this future is dropped, `pending` forgets the notification
+            let handled = inner.call(request).await?.status().is_success();
+            Ok(pending.acknowledge(handled).await)
+        })
+    }
+}
+
+/// A request that cannot be verified, answered without an acknowledgement
+fn reject(e: Error) -> Response {
+    let status = match &e {
+        Error::Signature { .. } | Error::Webhook(WebhookError::Stale { .. }) => {
+            StatusCode::UNAUTHORIZED
+        }
+        Error::Webhook(WebhookError::Store(_)) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::Webhook(_) | Error::Parse(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use ::axum::routing::post;
+    use ::axum::{Extension, Router};
+    use chrono::Utc;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::replay::{MemoryReplayStore, WebhookPolicy};
+    use crate::test_support::{alipay_notification, merchant_secret};
+
+    fn webhook() -> AlipayWebhook {
+        webhook_with(Arc::new(MemoryReplayStore::default()))
+    }
+
+    fn webhook_with(store: Arc<MemoryReplayStore>) -> AlipayWebhook {
+        let mut protocol = AlipayProtocol::new(&merchant_secret()).unwrap();
+        protocol.set_webhook_policy(WebhookPolicy {
+            replay_store: Some(store),
+            ..WebhookPolicy::default()
+        });
+        protocol.into()
+    }
+
+    /// Wait for a dropped guard to forget the notification on the blocking pool
+    async fn forgotten(store: &MemoryReplayStore) {
+        for _ in 0..500 {
+            if store.is_empty() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        panic!("the notification was not forgotten");
+    }
+
+    fn request(data: &WebhookData) -> Request {
+        Request::post(data.path.as_str())
+            .header("request-time", &data.request_time)
+            .header("signature", &data.header_signature)
+            .header("client-id", &data.client_id)
+            .body(Body::from(data.request_body.clone()))
+            .unwrap()
+    }
+
+    /// The HTTP status and the `resultStatus` of the acknowledgement, if any
+    async fn send(app: &Router, request: Request) -> (StatusCode, Option<String>) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let signed = response.headers().contains_key("signature");
+        let body = to_bytes(response.into_body(), MAX_BODY).await.unwrap();
+        let result = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .filter(|_| signed)
+            .and_then(|body| Some(body["result"]["resultStatus"].as_str()?.to_string()));
+        (status, result)
+    }
+
+    fn payment(payment_id: &str) -> WebhookData {
+        alipay_notification(
+            "/notify",
+            json!({
+                "notifyType": "PAYMENT_RESULT",
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+                "paymentRequestId": "order-1",
+                "paymentId": payment_id,
+                "paymentAmount": {"currency": "USD", "value": "100"}
+            }),
+            Utc::now(),
+        )
+    }
+
+    #[tokio::test]
+    async fn extractor_rejects_unverified_requests() {
+        async fn notify(notification: VerifiedNotification) -> Response {
+            match notification.notification() {
+                Notification::Payment(_) => notification.success().await,
+                _ => notification.failed().await,
+            }
+        }
+        let app = Router::new()
+            .route("/notify", post(notify))
+            .with_state(webhook());
+
+        let paid = payment("p1");
+        let ok = (StatusCode::OK, Some(String::from("S")));
+        assert_eq!(send(&app, request(&paid)).await, ok);
+        // a replay is acknowledged again
+        assert_eq!(send(&app, request(&paid)).await, ok);
+
+        let other = alipay_notification("/notify", json!({"paymentId": "p2"}), Utc::now());
+        assert_eq!(
+            send(&app, request(&other)).await,
+            (StatusCode::OK, Some(String::from("F")))
+        );
+
+        let unsigned = Request::post("/notify")
+            .header("client-id", "SANDBOX_TEST")
+            .body(Body::from(paid.request_body.clone()))
+            .unwrap();
+        assert_eq!(send(&app, unsigned).await, (StatusCode::BAD_REQUEST, None));
+
+        let mut tampered = payment("p3");
+        tampered.request_body = tampered.request_body.replace("p3", "p4");
+        assert_eq!(
+            send(&app, request(&tampered)).await,
+            (StatusCode::UNAUTHORIZED, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn extractor_forgets_notifications_that_were_not_acknowledged() {
+        async fn notify(notification: VerifiedNotification) -> Result<Response, StatusCode> {
+            match notification.notification() {
+                Notification::Payment(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+                _ => Ok(notification.success().await),
+            }
+        }
+        let store = Arc::new(MemoryReplayStore::default());
+        let app = Router::new()
+            .route("/notify", post(notify))
+            .with_state(webhook_with(store.clone()));
+
+        let paid = payment("p1");
+        let error = (StatusCode::INTERNAL_SERVER_ERROR, None);
+        assert_eq!(send(&app, request(&paid)).await, error);
+        forgotten(&store).await;
+        // not a replay: the handler did not acknowledge it
+        assert_eq!(send(&app, request(&paid)).await, error);
+    }
+
+    #[tokio::test]
+    async fn verifies_the_path_before_nesting() {
+        async fn notify(notification: VerifiedNotification) -> Response {
+            notification.success().await
+        }
+        let inner = Router::new()
+            .route("/notify", post(notify))
+            .with_state(webhook());
+        let layered = Router::new()
+            .route("/notify", post(|| async { StatusCode::NO_CONTENT }))
+            .layer(AlipayWebhookLayer::new(webhook()));
+        let app = Router::new()
+            .nest("/alipay", inner)
+            .nest("/layered", layered);
+
+        let notification =
+            |path: &str| alipay_notification(path, json!({"paymentId": "p1"}), Utc::now());
+        let ok = (StatusCode::OK, Some(String::from("S")));
+        assert_eq!(
+            send(&app, request(&notification("/alipay/notify"))).await,
+            ok
+        );
+        assert_eq!(
+            send(&app, request(&notification("/layered/notify"))).await,
+            ok
+        );
+    }
+
+    #[tokio::test]
+    async fn layer_forgets_notifications_when_the_route_panics() {
+        async fn notify() -> StatusCode {
+            panic!("the route failed")
+        }
+        let store = Arc::new(MemoryReplayStore::default());
+        let app = Router::new()
+            .route("/notify", post(notify))
+            .layer(AlipayWebhookLayer::new(webhook_with(store.clone())));
+
+        let answered = tokio::spawn(app.oneshot(request(&payment("p1")))).await;
+        assert!(answered.unwrap_err().is_panic());
+        // not acknowledged, its next delivery reaches the route again
+        forgotten(&store).await;
+    }
+
+    #[tokio::test]
+    async fn layer_acknowledges_by_the_route_status() {
+        let app = Router::new()
+            .route(
+                "/notify",
+                post(
+                    |Extension(notification): Extension<Notification>| async move {
+                        match notification {
+                            Notification::Payment(_) => StatusCode::NO_CONTENT,
+                            _ => StatusCode::INTERNAL_SERVER_ERROR,
+                        }
+                    },
+                ),
+            )
+            .layer(AlipayWebhookLayer::new(webhook()));
+
+        assert_eq!(
+            send(&app, request(&payment("p1"))).await,
+            (StatusCode::OK, Some(String::from("S")))
+        );
+        let other = alipay_notification("/notify", json!({"paymentId": "p2"}), Utc::now());
+        assert_eq!(
+            send(&app, request(&other)).await,
+            (StatusCode::OK, Some(String::from("F")))
+        );
+        // the failed notification was forgotten, its next delivery reaches the route again
+        assert_eq!(
+            send(&app, request(&other)).await,
+            (StatusCode::OK, Some(String::from("F")))
+        );
+
+        let mut tampered = payment("p3");
+        tampered.request_body = tampered.request_body.replace("p3", "p4");
+        assert_eq!(
+            send(&app, request(&tampered)).await,
+            (StatusCode::UNAUTHORIZED, None)
+        );
+    }
+}
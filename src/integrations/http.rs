@@ -0,0 +1,109 @@
+//! Notifications from `http` requests and acknowledgements as `http` responses.
+
+use ::http::header::{HeaderValue, CONTENT_TYPE};
+use ::http::{Request, Response, StatusCode};
+use bytes::Bytes;
+
+use crate::errors::{Error, WebhookError};
+use crate::models::{WebhookData, WebhookResponse};
+
+impl WebhookData {
+    /// Assemble a notification from the request Alipay sent, see [`WebhookData::from_headers`]
+    pub fn from_http_request<B: AsRef<[u8]>>(request: &Request<B>) -> Result<Self, Error> {
+        WebhookData::from_headers(
+            request.method().as_str(),
+            request.uri().path(),
+            request.headers(),
+            request.body().as_ref(),
+        )
+    }
+}
+
+impl TryFrom<Request<Bytes>> for WebhookData {
+    type Error = Error;
+
+    fn try_from(request: Request<Bytes>) -> Result<Self, Self::Error> {
+        WebhookData::from_http_request(&request)
+    }
+}
+
+impl TryFrom<WebhookResponse> for Response<Bytes> {
+    type Error = Error;
+
+    /// The `200 OK` answering Alipay, with the `signature`, `client-id` and `response-time` headers
+    fn try_from(response: WebhookResponse) -> Result<Self, Self::Error> {
+        let header = |name: &'static str, value: String| {
+            HeaderValue::try_from(value).map_err(|e| WebhookError::InvalidHeader {
+                name,
+                reason: e.to_string(),
+            })
+        };
+        let mut http_response = Response::new(Bytes::from(response.body));
+        *http_response.status_mut() = StatusCode::OK;
+        let headers = http_response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert("signature", header("signature", response.full_signature)?);
+        headers.insert("client-id", header("client-id", response.client_id)?);
+        headers.insert(
+            "response-time",
+            header("response-time", response.response_time)?,
+        );
+        Ok(http_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::models::WebhookResponseInput;
+    use crate::sansio::AlipayProtocol;
+    use crate::test_support::{alipay_notification, merchant_secret};
+
+    #[test]
+    fn converts_requests_and_acknowledgements() {
+        let sent = alipay_notification("/alipay/notify", json!({"paymentId": "p1"}), Utc::now());
+        let request = Request::post("https://shop.example/alipay/notify?source=alipay")
+            .header("Request-Time", &sent.request_time)
+            .header("Signature", &sent.header_signature)
+            .header("Client-Id", &sent.client_id)
+            .body(Bytes::from(sent.request_body.clone()))
+            .unwrap();
+        let data = WebhookData::try_from(request).unwrap();
+        assert_eq!(data.path, "/alipay/notify");
+        let protocol = AlipayProtocol::new(&merchant_secret()).unwrap();
+        protocol.verify_notification(&data).unwrap();
+
+        let request = Request::post("/alipay/notify")
+            .header("Signature", &sent.header_signature)
+            .header("Client-Id", &sent.client_id)
+            .body(Bytes::new())
+            .unwrap();
+        assert!(matches!(
+            WebhookData::from_http_request(&request),
+            Err(Error::Webhook(WebhookError::MissingHeader("request-time")))
+        ));
+
+        let acknowledgement = protocol
+            .webhook_success_response(WebhookResponseInput {
+                method: data.method,
+                path: data.path,
+                client_id: data.client_id,
+            })
+            .unwrap();
+        let response = Response::try_from(acknowledgement.clone()).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["signature"],
+            acknowledgement.full_signature.as_str()
+        );
+        assert_eq!(response.headers()["client-id"], "SANDBOX_TEST");
+        assert_eq!(
+            response.headers()["response-time"],
+            acknowledgement.response_time.as_str()
+        );
+        assert_eq!(response.body(), acknowledgement.body.as_bytes());
+    }
+}
@@ -0,0 +1,25 @@
+//! GraphQL support for the Alipay models.
+
+use ::juniper::{graphql_scalar, ParseScalarResult, ParseScalarValue, Value};
+
+use crate::models::DisplayType;
+
+/// `DisplayType` is a string scalar rather than a GraphQL enum,
+/// so values Alipay adds later still reach the client.
+#[graphql_scalar(description = "How a code is displayed, e.g. TEXT or BIGIMAGE")]
+impl<S> GraphQLScalar for DisplayType
+where
+    S: ScalarValue,
+{
+    fn resolve(&self) -> Value {
+        Value::scalar(self.to_string())
+    }
+
+    fn from_input_value(v: &InputValue) -> Option<DisplayType> {
+        v.as_string_value().and_then(|s| s.parse().ok())
+    }
+
+    fn from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+        <String as ParseScalarValue<S>>::from_str(value)
+    }
+}
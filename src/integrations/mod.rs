@@ -0,0 +1,6 @@
+#[cfg(feature = "axum")]
+pub(crate) mod axum;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "juniper")]
+mod juniper;
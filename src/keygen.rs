@@ -0,0 +1,185 @@
+//! Generate the merchant key pair of a new Alipay account.
+//!
+//! Upload [`KeyPair::public_key_base64`] in the Alipay developer portal and
+//! keep [`KeyPair::private_key_pem`] as `private_key_pem` or in a key file.
+//!
+//! ```no_run
+//! use alipay_global::keygen::KeyPair;
+//!
+//! let pair = KeyPair::generate().unwrap();
+//! pair.save("merchant_private_key.pem", "merchant_public_key.pem").unwrap();
+//! println!("{}", pair.public_key_base64().unwrap());
+//! ```
+
+use std::io::Write;
+use std::path::Path;
+
+use base64::Engine;
+use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::{Hash, PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use super::errors::{Key, KeyError};
+use super::models::HasPrivateKey;
+use super::signer::sign_with_key;
+
+/// The key size Alipay expects
+pub const KEY_BITS: usize = 2048;
+
+/// Signed and verified by [`KeyPair::self_check`]
+const PROBE: &[u8] = b"alipay-global key pair self-check";
+
+/// A merchant private key and its public key
+pub struct KeyPair {
+    private_key: RsaPrivateKey,
+    public_key: RsaPublicKey,
+}
+
+impl KeyPair {
+    /// Generate an RSA-2048 key pair and check that it signs and verifies
+    pub fn generate() -> Result<Self, KeyError> {
+        let private_key = RsaPrivateKey::new(&mut rand::rngs::OsRng, KEY_BITS)
+            .map_err(|e| invalid(format!("cannot generate the key: {}", e)))?;
+        let pair = Self::from_private_key(private_key);
+        pair.self_check()?;
+        Ok(pair)
+    }
+
+    pub fn from_private_key(private_key: RsaPrivateKey) -> Self {
+        Self {
+            public_key: RsaPublicKey::from(&private_key),
+            private_key,
+        }
+    }
+
+    pub fn private_key(&self) -> &RsaPrivateKey {
+        &self.private_key
+    }
+
+    pub fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+
+    /// Sign a probe message with the private key and verify it with the public key
+    pub fn self_check(&self) -> Result<(), KeyError> {
+        let digest: [u8; 32] = Sha256::digest(PROBE).into();
+        let signature = sign_with_key(&self.private_key, &digest)
+            .map_err(|e| invalid(format!("the key cannot sign: {}", e)))?;
+        self.public_key
+            .verify(
+                PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+                &digest,
+                &signature,
+            )
+            .map_err(|_| {
+                invalid(String::from(
+                    "the public key rejects the key's own signature",
+                ))
+            })
+    }
+
+    /// The private key as a PKCS#8 PEM, zeroed when dropped
+    pub fn private_key_pem(&self) -> Result<Zeroizing<String>, KeyError> {
+        self.private_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|e| invalid(format!("cannot encode the private key: {}", e)))
+    }
+
+    /// The public key as an SPKI PEM
+    pub fn public_key_pem(&self) -> Result<String, KeyError> {
+        self.public_key
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(|e| invalid(format!("cannot encode the public key: {}", e)))
+    }
+
+    /// The public key as the single line of base64 the Alipay portal asks for
+    pub fn public_key_base64(&self) -> Result<String, KeyError> {
+        let der = self
+            .public_key
+            .to_public_key_der()
+            .map_err(|e| invalid(format!("cannot encode the public key: {}", e)))?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(der.as_ref()))
+    }
+
+    /// Run the self-check, then write both PEM files.
+    /// On Unix the private key file is only readable by its owner.
+    pub fn save(
+        &self,
+        private_key_path: impl AsRef<Path>,
+        public_key_path: impl AsRef<Path>,
+    ) -> std::io::Result<()> {
+        let to_io = |e: KeyError| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        self.self_check().map_err(to_io)?;
+        let private_key_pem = self.private_key_pem().map_err(to_io)?;
+        let public_key_pem = self.public_key_pem().map_err(to_io)?;
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(private_key_path)?
+            .write_all(private_key_pem.as_bytes())?;
+        std::fs::write(public_key_path, public_key_pem)
+    }
+}
+
+impl HasPrivateKey for KeyPair {
+    fn get_private_key(&self) -> Result<RsaPrivateKey, KeyError> {
+        Ok(self.private_key.clone())
+    }
+}
+
+fn invalid(reason: String) -> KeyError {
+    KeyError::Invalid {
+        key: Key::MerchantPrivate,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{parse_private_key, parse_public_key};
+    use crate::models::load_private_key;
+    use crate::test_support::fixture;
+
+    #[test]
+    fn generates_a_pair_the_loaders_accept() {
+        let pair = KeyPair::generate().unwrap();
+        assert_eq!(rsa::PublicKeyParts::size(pair.public_key()) * 8, KEY_BITS);
+
+        let private_key = parse_private_key(pair.private_key_pem().unwrap().as_bytes()).unwrap();
+        assert_eq!(&private_key, pair.private_key());
+        assert_eq!(pair.get_private_key().unwrap(), private_key);
+
+        let portal = pair.public_key_base64().unwrap();
+        assert!(!portal.contains('\n') && !portal.contains("-----"));
+        assert_eq!(
+            &parse_public_key(portal.as_bytes()).unwrap(),
+            pair.public_key()
+        );
+        assert_eq!(
+            &parse_public_key(pair.public_key_pem().unwrap().as_bytes()).unwrap(),
+            pair.public_key()
+        );
+    }
+
+    #[test]
+    fn self_check_catches_mismatched_keys() {
+        let merchant = load_private_key(&fixture("merchant_private_key.pem")).unwrap();
+        let alipay = load_private_key(&fixture("alipay_private_key.pem")).unwrap();
+        let pair = KeyPair::from_private_key(merchant);
+        pair.self_check().unwrap();
+
+        let mismatched = KeyPair {
+            public_key: RsaPublicKey::from(&alipay),
+            ..pair
+        };
+        assert!(matches!(
+            mismatched.self_check(),
+            Err(KeyError::Invalid { .. })
+        ));
+    }
+}
@@ -0,0 +1,287 @@
+//! Load RSA keys in whatever form they were exported.
+//!
+//! PKCS#1 and PKCS#8 private keys, SPKI and PKCS#1 public keys are accepted
+//! as PEM, as DER, or as the bare base64 the Alipay portal shows, with or
+//! without line breaks. Password-protected `ENCRYPTED PRIVATE KEY`s are
+//! decrypted in memory when a passphrase is given.
+//!
+//! [`AlipayPublicKeys`] holds the Alipay public keys by `keyVersion`, so
+//! signatures made with the old and the new key verify while Alipay rotates.
+
+use base64::Engine;
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+use super::errors::{Error, Key, KeyError};
+use super::signer::DEFAULT_KEY_VERSION;
+
+/// Parse a merchant private key, detecting its format
+pub fn parse_private_key(input: &[u8]) -> Result<RsaPrivateKey, KeyError> {
+    decode_private_key(input, None)
+}
+
+/// Parse a merchant private key that may be an encrypted PKCS#8 key
+pub fn parse_private_key_with_passphrase(
+    input: &[u8],
+    passphrase: &[u8],
+) -> Result<RsaPrivateKey, KeyError> {
+    decode_private_key(input, Some(passphrase))
+}
+
+pub(crate) fn decode_private_key(
+    input: &[u8],
+    passphrase: Option<&[u8]>,
+) -> Result<RsaPrivateKey, KeyError> {
+    let der = to_der(Key::MerchantPrivate, input)?;
+    if let Ok(encrypted) = pkcs8::EncryptedPrivateKeyInfo::try_from(der.as_slice()) {
+        let passphrase = passphrase.ok_or(KeyError::PassphraseRequired(Key::MerchantPrivate))?;
+        // the decrypted document is zeroed when dropped
+        let decrypted = encrypted.decrypt(passphrase).map_err(|_| {
+            invalid(
+                Key::MerchantPrivate,
+                "cannot decrypt the key, the passphrase may be wrong",
+            )
+        })?;
+        return RsaPrivateKey::from_pkcs8_der(decrypted.as_bytes()).map_err(|_| {
+            invalid(
+                Key::MerchantPrivate,
+                "the decrypted key is not an RSA private key",
+            )
+        });
+    }
+    RsaPrivateKey::from_pkcs8_der(&der)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_der(&der))
+        .map_err(|_| {
+            invalid(
+                Key::MerchantPrivate,
+                "not a PKCS#1 or PKCS#8 RSA private key",
+            )
+        })
+}
+
+/// Parse an Alipay public key, detecting its format
+pub fn parse_public_key(input: &[u8]) -> Result<RsaPublicKey, KeyError> {
+    let der = to_der(Key::AlipayPublic, input)?;
+    RsaPublicKey::from_public_key_der(&der)
+        .or_else(|_| RsaPublicKey::from_pkcs1_der(&der))
+        .map_err(|_| invalid(Key::AlipayPublic, "not an SPKI or PKCS#1 RSA public key"))
+}
+
+/// Whether an Alipay public key is a PKCS#1 `RSA PUBLIC KEY` rather than SPKI
+pub(crate) fn is_pkcs1_public_key(input: &[u8]) -> bool {
+    to_der(Key::AlipayPublic, input).is_ok_and(|der| {
+        RsaPublicKey::from_public_key_der(&der).is_err()
+            && RsaPublicKey::from_pkcs1_der(&der).is_ok()
+    })
+}
+
+/// The lowercase hex SHA-256 of the SPKI DER of a public key, as printed by
+/// `openssl pkey -pubin -outform DER | sha256sum`
+pub fn fingerprint(public_key: &RsaPublicKey) -> Result<String, Error> {
+    let der = public_key
+        .to_public_key_der()
+        .map_err(|e| Error::config_caused_by("cannot encode the public key", e.to_string()))?;
+    Ok(Sha256::digest(der.as_ref())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Parse the merchant private key held by an environment variable
+pub fn private_key_from_env(name: &str) -> Result<RsaPrivateKey, KeyError> {
+    parse_private_key(read_env(Key::MerchantPrivate, name)?.as_bytes())
+}
+
+/// Parse the Alipay public key held by an environment variable
+pub fn public_key_from_env(name: &str) -> Result<RsaPublicKey, KeyError> {
+    parse_public_key(read_env(Key::AlipayPublic, name)?.as_bytes())
+}
+
+pub(crate) fn read_env(key: Key, name: &str) -> Result<String, KeyError> {
+    std::env::var(name).map_err(|e| KeyError::Env {
+        key,
+        name: name.to_string(),
+        source: e,
+    })
+}
+
+/// The Alipay public keys a signature is verified with, by `keyVersion`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlipayPublicKeys {
+    keys: Vec<(String, RsaPublicKey)>,
+}
+
+impl AlipayPublicKeys {
+    /// A single key with version `1`
+    pub fn new(key: RsaPublicKey) -> Self {
+        Self::with_key_version(key, DEFAULT_KEY_VERSION)
+    }
+
+    pub fn with_key_version(key: RsaPublicKey, key_version: impl Into<String>) -> Self {
+        Self {
+            keys: vec![(key_version.into(), key)],
+        }
+    }
+
+    /// Add a key, replacing the one with the same version
+    pub fn insert(&mut self, key_version: impl Into<String>, key: RsaPublicKey) {
+        let key_version = key_version.into();
+        match self.keys.iter_mut().find(|(v, _)| *v == key_version) {
+            Some(entry) => entry.1 = key,
+            None => self.keys.push((key_version, key)),
+        }
+    }
+
+    /// The key for the `keyVersion` of a signature header, `1` when it has none.
+    /// A single key is used whatever the version, as before keys were versioned.
+    pub fn get(&self, key_version: Option<&str>) -> Result<&RsaPublicKey, Error> {
+        let key_version = key_version.unwrap_or(DEFAULT_KEY_VERSION);
+        match self.keys.iter().find(|(v, _)| v == key_version) {
+            Some((_, key)) => Ok(key),
+            None if self.keys.len() == 1 => Ok(&self.keys[0].1),
+            None => Err(Error::signature(format!(
+                "no alipay public key has version {}",
+                key_version
+            ))),
+        }
+    }
+
+    /// The versions of all keys, in the order they were added
+    pub fn key_versions(&self) -> impl Iterator<Item = &str> {
+        self.keys.iter().map(|(v, _)| v.as_str())
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &RsaPublicKey> {
+        self.keys.iter().map(|(_, key)| key)
+    }
+}
+
+impl From<RsaPublicKey> for AlipayPublicKeys {
+    fn from(value: RsaPublicKey) -> Self {
+        Self::new(value)
+    }
+}
+
+/// DER is passed through, PEM and bare base64 are decoded
+fn to_der(key: Key, input: &[u8]) -> Result<Vec<u8>, KeyError> {
+    let text = match std::str::from_utf8(input) {
+        Ok(text) => text.trim(),
+        // a DER key starts with a SEQUENCE tag, which is not valid UTF-8 in practice
+        Err(_) => return Ok(input.to_vec()),
+    };
+    if text.is_empty() {
+        return Err(invalid(key, "the key is empty"));
+    }
+    // drop the PEM boundaries, whatever label they carry
+    let body: String = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("-----"))
+        .flat_map(|line| line.chars().filter(|c| !c.is_whitespace()))
+        .collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(body)
+        .map_err(|e| invalid(key, &format!("neither PEM, DER nor base64: {}", e)))
+}
+
+fn invalid(key: Key, reason: &str) -> KeyError {
+    KeyError::Invalid {
+        key,
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::pkcs8::EncodePrivateKey;
+
+    use super::*;
+    use crate::test_support::fixture;
+
+    #[test]
+    fn detects_private_key_formats() {
+        let key = parse_private_key(fixture("merchant_private_key.pem").as_bytes()).unwrap();
+
+        let pkcs8_pem = key.to_pkcs8_pem(Default::default()).unwrap();
+        assert_eq!(parse_private_key(pkcs8_pem.as_bytes()).unwrap(), key);
+
+        let pkcs8_der = key.to_pkcs8_der().unwrap();
+        assert_eq!(parse_private_key(pkcs8_der.as_ref()).unwrap(), key);
+
+        let pkcs1_der = key.to_pkcs1_der().unwrap();
+        let bare = base64::engine::general_purpose::STANDARD.encode(pkcs1_der.as_ref());
+        assert_eq!(parse_private_key(bare.as_bytes()).unwrap(), key);
+    }
+
+    #[test]
+    fn detects_public_key_formats() {
+        let pem = fixture("alipay_public_key.pem");
+        let key = parse_public_key(pem.as_bytes()).unwrap();
+
+        let der = key.to_public_key_der().unwrap();
+        assert_eq!(parse_public_key(der.as_ref()).unwrap(), key);
+
+        let bare: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+        assert_eq!(parse_public_key(bare.as_bytes()).unwrap(), key);
+    }
+
+    #[test]
+    fn decrypts_encrypted_pkcs8_keys() {
+        let plain = parse_private_key(fixture("merchant_private_key.pem").as_bytes()).unwrap();
+        let encrypted = fixture("merchant_private_key_encrypted.pem");
+
+        let key =
+            parse_private_key_with_passphrase(encrypted.as_bytes(), b"correct-horse").unwrap();
+        assert_eq!(key, plain);
+        assert!(matches!(
+            parse_private_key(encrypted.as_bytes()),
+            Err(KeyError::PassphraseRequired(Key::MerchantPrivate))
+        ));
+        assert!(matches!(
+            parse_private_key_with_passphrase(encrypted.as_bytes(), b"wrong"),
+            Err(KeyError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn selects_the_public_key_by_version() {
+        let old = parse_public_key(fixture("alipay_public_key.pem").as_bytes()).unwrap();
+        let new = parse_public_key(fixture("merchant_public_key.pem").as_bytes()).unwrap();
+
+        let mut keys = AlipayPublicKeys::new(old.clone());
+        // a single key verifies whatever version Alipay sends
+        assert_eq!(keys.get(Some("7")).unwrap(), &old);
+
+        keys.insert("2", new.clone());
+        assert_eq!(keys.get(None).unwrap(), &old);
+        assert_eq!(keys.get(Some("2")).unwrap(), &new);
+        assert!(matches!(keys.get(Some("7")), Err(Error::Signature { .. })));
+    }
+
+    #[test]
+    fn fingerprints_the_spki_der() {
+        let key = parse_public_key(fixture("alipay_public_key.pem").as_bytes()).unwrap();
+        // openssl pkey -pubin -in alipay_public_key.pem -outform DER | sha256sum
+        assert_eq!(
+            fingerprint(&key).unwrap(),
+            "7b6da1d0413f1f50fa4f8c955b518e026fd112266101d31b13261932bf1c0049"
+        );
+    }
+
+    #[test]
+    fn rejects_garbage_and_missing_variables() {
+        assert!(matches!(
+            parse_public_key(b"not a key"),
+            Err(KeyError::Invalid {
+                key: Key::AlipayPublic,
+                ..
+            })
+        ));
+        assert!(matches!(
+            private_key_from_env("ALIPAY_GLOBAL_TEST_UNSET_VARIABLE"),
+            Err(KeyError::Env { .. })
+        ));
+    }
+}
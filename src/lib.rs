@@ -1,21 +1,22 @@
 //! use [Alipay Global](https://global.alipay.com) without any pain.
 //!
 //! # Example
-//! ```
-//! use alipay_global::pay::cashier_payment;
+//! ```no_run
+//! use alipay_global::AlipayClient;
 //! use alipay_global::models::*;
-//! use std::path::PathBuf;
 //!
 //! // load client id and private pem key from environment for test purpose
 //! let client_id = std::env::var("CLIENT_ID").expect("Missing CLIENT_ID environment variable");
 //! let private_key_pem_path = std::env::var("PEM_PATH").expect("Missing PEM_PATH environment variable");
+//! let alipay_public_key_pem_path = std::env::var("ALIPAY_PEM_PATH").expect("Missing ALIPAY_PEM_PATH environment variable");
 //!
 //! // Client secret object contains all the information regarding your Alipay Global Account
-//! let secret = AlipayClientSecret {client_id: String::from(client_id),
-//!     sandbox: true,
-//!     private_key_pem: None,
-//!     private_key_pem_file: Some(Box::new(PathBuf::from(&private_key_pem_path))),
-//! };
+//! let secret = AlipayClientSecret::new(client_id)
+//!     .with_private_key_file(private_key_pem_path)
+//!     .with_alipay_public_key_file(alipay_public_key_pem_path);
+//!
+//! // Build the client once and share it, keys are parsed only here
+//! let client = AlipayClient::new(&secret).unwrap();
 //!
 //! // CashierPayment Object contains order info
 //! let p = CashierPaymentSimple {
@@ -30,16 +31,46 @@
 //! };
 //!
 //! // Call the API
-//! let r = cashier_payment(&secret, &p);
+//! let r = client.pay(&p);
 //! ```
 extern crate rsa;
 pub use rsa::Hash;
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(any(feature = "async", feature = "axum"))]
+mod blocking;
+pub mod classify;
+mod client;
+pub mod endpoint;
 pub mod errors;
-mod integrations;
 pub mod inquiry;
+mod integrations;
+pub mod keygen;
+pub mod keys;
 pub mod models;
+pub mod notification;
 pub mod pay;
 pub mod refund;
+pub mod reload;
+pub mod replay;
+pub mod resolve;
 mod response;
+pub mod retry;
+pub mod sansio;
 mod sign;
+pub mod signature;
+pub mod signer;
+#[cfg(test)]
+mod test_support;
+pub mod transport;
+pub mod validate;
 pub mod webhook;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncAlipayClient;
+pub use client::AlipayClient;
+pub use endpoint::{Endpoint, Region};
+#[cfg(feature = "axum")]
+pub use integrations::axum::{
+    AlipayWebhook, AlipayWebhookLayer, AlipayWebhookService, VerifiedNotification,
+};
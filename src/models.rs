@@ -1,84 +1,237 @@
-use super::errors::Error;
-use base64::Engine;
+use super::endpoint::{Endpoint, Environment, Region};
+use super::errors::{Error, Key, KeyError};
+use super::keys::{decode_private_key, parse_private_key, parse_public_key, read_env};
 use chrono::{DateTime, Utc};
-use juniper::{GraphQLEnum, GraphQLObject};
-use rsa::{
-    pkcs1::{DecodeRsaPrivateKey, Error as Pkcs1Error},
-    pkcs8::{spki::Error as Pkcs8Error, DecodePublicKey},
-    Hash, PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey,
-};
+#[cfg(feature = "juniper")]
+use juniper::GraphQLObject;
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::fs::read_to_string;
-use std::path::PathBuf;
-use std::string::ToString;
-use strum_macros::Display;
-// use std::io::{Error as StdError, Result, ErrorKind};
-
-pub enum AlipayAction {
-    PAY,
-    REFUND,
-    INQUIRY,
-}
-
-impl ToString for AlipayAction {
-    fn to_string(&self) -> String {
-        match self {
-            AlipayAction::PAY => String::from("pay"),
-            AlipayAction::REFUND => String::from("refund"),
-            AlipayAction::INQUIRY => String::from("inquiryPayment"),
-        }
-    }
-}
+use std::fmt;
+use std::path::{Path, PathBuf};
+use strum_macros::{Display, EnumString};
 
 /// Alipay Client Info and Secret
+///
+/// The secret only holds credentials, the API to call is chosen by each operation,
+/// so one secret can be loaded from config and used for every endpoint.
+/// The private key and its passphrase are never serialized nor printed by `Debug`.
+///
+/// ```
+/// use alipay_global::models::AlipayClientSecret;
+///
+/// let secret = AlipayClientSecret::new("SANDBOX_5Y...")
+///     .with_private_key_file("private_key.pem")
+///     .with_alipay_public_key_file("alipay_public_key.pem");
+/// assert!(secret.sandbox);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[non_exhaustive]
 pub struct AlipayClientSecret {
-    pub action: AlipayAction,
     pub client_id: String,
     pub sandbox: bool,
+    /// Allow calls to the live API. Leave it unset in staging so it can never move real money.
+    #[serde(default)]
+    pub live_payments_allowed: bool,
+    /// The gateway the account was onboarded on
+    #[serde(default)]
+    pub region: Region,
+    /// Send requests to this base URL instead of the region gateway, e.g. a local stand-in server
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// The merchant private key, as PKCS#1 or PKCS#8, PEM or bare base64
+    #[serde(skip_serializing)]
     pub private_key_pem: Option<String>,
     pub private_key_pem_file: Option<Box<PathBuf>>,
+    /// The name of an environment variable holding the merchant private key
+    #[serde(default)]
+    pub private_key_env: Option<String>,
+    /// The passphrase of an `ENCRYPTED PRIVATE KEY`
+    #[serde(default, skip_serializing)]
+    pub private_key_passphrase: Option<String>,
+    /// The name of an environment variable holding the passphrase
+    #[serde(default)]
+    pub private_key_passphrase_env: Option<String>,
+    /// The version of the merchant private key, sent as `keyVersion`. `1` when unset.
+    #[serde(default)]
+    pub key_version: Option<String>,
+    /// The Alipay public key, as PEM or the bare base64 the portal shows
     pub alipay_public_key_pem: Option<String>,
     pub alipay_public_key_pem_file: Option<Box<PathBuf>>,
+    /// The name of an environment variable holding the Alipay public key
+    #[serde(default)]
+    pub alipay_public_key_env: Option<String>,
+    /// Keys known to belong to one environment, a client refuses them in the other
+    #[serde(default)]
+    pub pinned_keys: Vec<PinnedKey>,
+}
+
+impl fmt::Debug for AlipayClientSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |secret: &Option<String>| secret.as_ref().map(|_| "<redacted>");
+        f.debug_struct("AlipayClientSecret")
+            .field("client_id", &self.client_id)
+            .field("sandbox", &self.sandbox)
+            .field("live_payments_allowed", &self.live_payments_allowed)
+            .field("region", &self.region)
+            .field("base_url", &self.base_url)
+            .field("private_key_pem", &redacted(&self.private_key_pem))
+            .field("private_key_pem_file", &self.private_key_pem_file)
+            .field("private_key_env", &self.private_key_env)
+            .field(
+                "private_key_passphrase",
+                &redacted(&self.private_key_passphrase),
+            )
+            .field(
+                "private_key_passphrase_env",
+                &self.private_key_passphrase_env,
+            )
+            .field("key_version", &self.key_version)
+            .field("alipay_public_key_pem", &self.alipay_public_key_pem)
+            .field(
+                "alipay_public_key_pem_file",
+                &self.alipay_public_key_pem_file,
+            )
+            .field("alipay_public_key_env", &self.alipay_public_key_env)
+            .field("pinned_keys", &self.pinned_keys)
+            .finish()
+    }
+}
+
+/// A public key, merchant or Alipay, that must only be used in `environment`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinnedKey {
+    pub environment: Environment,
+    /// The [`fingerprint`](crate::keys::fingerprint) of the public key
+    pub fingerprint: String,
 }
 
 impl HasPrivateKey for AlipayClientSecret {
-    fn get_private_key(&self) -> Result<RsaPrivateKey, Pkcs1Error> {
-        let key: String;
-        if (self.private_key_pem_file.is_some()) {
-            let s = read_to_string(self.private_key_pem_file.clone().unwrap().as_path()).unwrap();
-            key = s;
-        } else {
-            key = format_pkcs1_private_key(&self.private_key_pem.clone().unwrap());
-        }
+    fn get_private_key(&self) -> Result<RsaPrivateKey, KeyError> {
+        let key = match (
+            &self.private_key_pem_file,
+            &self.private_key_pem,
+            &self.private_key_env,
+        ) {
+            (Some(path), _, _) => read_key_file(Key::MerchantPrivate, path)?,
+            (None, Some(pem), _) => pem.clone().into_bytes(),
+            (None, None, Some(name)) => read_env(Key::MerchantPrivate, name)?.into_bytes(),
+            (None, None, None) => return Err(KeyError::Missing(Key::MerchantPrivate)),
+        };
+        let passphrase = self.private_key_passphrase()?;
 
         // get private obj
-        load_private_key(&key)
+        decode_private_key(&key, passphrase.as_deref().map(str::as_bytes))
     }
 }
 
-impl HasPublicKey for AlipayClientSecret {
-    fn get_public_key(&self) -> Result<RsaPublicKey, Pkcs8Error> {
-        let key: String;
-        if (self.alipay_public_key_pem_file.is_some()) {
-            let s =
-                read_to_string(self.alipay_public_key_pem_file.clone().unwrap().as_path()).unwrap();
-            key = s;
-        } else {
-            key = format_pem_public_key(&self.alipay_public_key_pem.clone().unwrap());
+impl AlipayClientSecret {
+    /// A secret for `client_id`, in the sandbox when it is a sandbox client id.
+    /// It has no keys yet, set them with the `with_*` methods or the fields.
+    pub fn new(client_id: impl Into<String>) -> Self {
+        let client_id = client_id.into();
+        Self {
+            sandbox: Environment::of_client_id(&client_id).is_sandbox(),
+            client_id,
+            ..Self::default()
+        }
+    }
+
+    /// Read the merchant private key from a PEM file
+    pub fn with_private_key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.private_key_pem_file = Some(Box::new(path.into()));
+        self
+    }
+
+    /// Use the merchant private key given as PEM or bare base64
+    pub fn with_private_key_pem(mut self, pem: impl Into<String>) -> Self {
+        self.private_key_pem = Some(pem.into());
+        self
+    }
+
+    /// Read the merchant private key from the environment variable `name`
+    pub fn with_private_key_env(mut self, name: impl Into<String>) -> Self {
+        self.private_key_env = Some(name.into());
+        self
+    }
+
+    /// Read the Alipay public key from a PEM file
+    pub fn with_alipay_public_key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.alipay_public_key_pem_file = Some(Box::new(path.into()));
+        self
+    }
+
+    /// Use the Alipay public key given as PEM or the bare base64 the portal shows
+    pub fn with_alipay_public_key_pem(mut self, pem: impl Into<String>) -> Self {
+        self.alipay_public_key_pem = Some(pem.into());
+        self
+    }
+
+    /// Read the Alipay public key from the environment variable `name`
+    pub fn with_alipay_public_key_env(mut self, name: impl Into<String>) -> Self {
+        self.alipay_public_key_env = Some(name.into());
+        self
+    }
+
+    pub fn environment(&self) -> Environment {
+        Environment::from_sandbox(self.sandbox)
+    }
+
+    /// The passphrase of the merchant private key, if one is configured
+    pub(crate) fn private_key_passphrase(&self) -> Result<Option<String>, KeyError> {
+        match (
+            &self.private_key_passphrase,
+            &self.private_key_passphrase_env,
+        ) {
+            (Some(passphrase), _) => Ok(Some(passphrase.clone())),
+            (None, Some(name)) => read_env(Key::MerchantPrivate, name).map(Some),
+            (None, None) => Ok(None),
         }
+    }
+}
 
+impl HasPublicKey for AlipayClientSecret {
+    fn get_public_key(&self) -> Result<RsaPublicKey, KeyError> {
         // get private obj
-        load_public_key(&key)
+        parse_public_key(&self.alipay_public_key_bytes()?)
+    }
+}
+
+impl AlipayClientSecret {
+    /// The Alipay public key as configured, before it is parsed
+    pub(crate) fn alipay_public_key_bytes(&self) -> Result<Vec<u8>, KeyError> {
+        Ok(
+            match (
+                &self.alipay_public_key_pem_file,
+                &self.alipay_public_key_pem,
+                &self.alipay_public_key_env,
+            ) {
+                (Some(path), _, _) => read_key_file(Key::AlipayPublic, path)?,
+                (None, Some(pem), _) => pem.clone().into_bytes(),
+                (None, None, Some(name)) => read_env(Key::AlipayPublic, name)?.into_bytes(),
+                (None, None, None) => return Err(KeyError::Missing(Key::AlipayPublic)),
+            },
+        )
     }
 }
 
-pub fn load_private_key(private_key_str: &str) -> Result<RsaPrivateKey, Pkcs1Error> {
-    RsaPrivateKey::from_pkcs1_pem(&private_key_str)
+/// Key files may hold PEM or DER, so they are read as bytes
+pub(crate) fn read_key_file(key: Key, path: &Path) -> Result<Vec<u8>, KeyError> {
+    std::fs::read(path).map_err(|source| KeyError::Read {
+        key,
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Parse a private key in any format [`parse_private_key`] detects
+pub fn load_private_key(private_key_str: &str) -> Result<RsaPrivateKey, KeyError> {
+    parse_private_key(private_key_str.as_bytes())
 }
 
-pub fn load_public_key(public_key_str: &str) -> Result<RsaPublicKey, Pkcs8Error> {
-    RsaPublicKey::from_public_key_pem(&public_key_str)
+/// Parse a public key in any format [`parse_public_key`] detects
+pub fn load_public_key(public_key_str: &str) -> Result<RsaPublicKey, KeyError> {
+    parse_public_key(public_key_str.as_bytes())
 }
 
 const PUBLIC_KEY_PREFIX: &str = "-----BEGIN PUBLIC KEY-----";
@@ -150,11 +303,16 @@ pub trait Signable {
 
 /// A Trait contains private key data
 pub trait HasPrivateKey {
-    fn get_private_key(&self) -> Result<RsaPrivateKey, Pkcs1Error>;
+    fn get_private_key(&self) -> Result<RsaPrivateKey, KeyError>;
+}
+
+/// A Trait for request bodies that belong to a single Alipay API
+pub trait HasEndpoint: Signable {
+    fn endpoint(&self) -> Endpoint;
 }
 
 pub trait HasPublicKey {
-    fn get_public_key(&self) -> Result<RsaPublicKey, Pkcs8Error>;
+    fn get_public_key(&self) -> Result<RsaPublicKey, KeyError>;
 }
 
 /// Payment Cashier Request Object
@@ -186,9 +344,9 @@ pub struct CashierPaymentFull {
     pub env: Env,
 }
 
-impl CashierPaymentFull {
-    pub fn to_string(&self) -> String {
-        serde_json::to_value(self).unwrap().to_string()
+impl std::fmt::Display for CashierPaymentFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_value())
     }
 }
 
@@ -225,30 +383,41 @@ impl Signable for CashierPaymentFull {
     }
 }
 
+impl HasEndpoint for CashierPaymentFull {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Pay
+    }
+}
+
 pub struct RequestEnv {
     pub path: String,
     pub domain: String,
 }
-impl From<&AlipayClientSecret> for RequestEnv {
-    fn from(value: &AlipayClientSecret) -> Self {
-        if value.sandbox {
-            Self {
-                path: String::from(format!(
-                    "/ams/sandbox/api/v1/payments/{}",
-                    value.action.to_string()
-                )),
-                domain: String::from("https://open-global.alipay.com"),
-            }
-        } else {
-            Self {
-                path: String::from(format!("/ams/api/v1/payments/{}", value.action.to_string())),
-                domain: String::from("https://open-global.alipay.com"),
-            }
-        }
+/// Split `https://host:port/prefix/` into `https://host:port` and `/prefix`
+fn split_base_url(base_url: &str) -> (&str, &str) {
+    let base_url = base_url.trim_end_matches('/');
+    let authority_start = base_url.find("://").map(|i| i + 3).unwrap_or(0);
+    match base_url[authority_start..].find('/') {
+        Some(i) => base_url.split_at(authority_start + i),
+        None => (base_url, ""),
     }
 }
 
 impl RequestEnv {
+    pub fn new(sandbox: bool, endpoint: Endpoint) -> Self {
+        Self::with_base_url(Region::default().domain(), sandbox, endpoint)
+    }
+
+    /// Target `base_url` instead of the default gateway.
+    /// A path in `base_url` becomes part of the signed path, so it matches what the server sees.
+    pub fn with_base_url(base_url: &str, sandbox: bool, endpoint: Endpoint) -> Self {
+        let (domain, prefix) = split_base_url(base_url);
+        Self {
+            path: format!("{}{}", prefix, endpoint.path(sandbox)),
+            domain: String::from(domain),
+        }
+    }
+
     pub fn get_request_url(&self) -> String {
         self.domain.clone() + self.path.as_str()
     }
@@ -284,12 +453,13 @@ pub struct Env {
 impl From<&CashierPaymentSimple> for Env {
     fn from(value: &CashierPaymentSimple) -> Self {
         let CashierPaymentSimple { terminal_type, .. } = value;
-        let tt = terminal_type.clone().unwrap_or(TerminalType::WEB);
+        let tt = terminal_type.unwrap_or(TerminalType::WEB);
         Self { terminal_type: tt }
     }
 }
 
 #[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
 pub enum TerminalType {
     WEB,
     WAP,
@@ -351,7 +521,7 @@ pub struct PaymentMethod {
 }
 
 impl From<&CashierPaymentSimple> for PaymentMethod {
-    fn from(value: &CashierPaymentSimple) -> Self {
+    fn from(_value: &CashierPaymentSimple) -> Self {
         Self {
             payment_method_type: String::from("ALIPAY_CN"),
         }
@@ -438,6 +608,7 @@ pub struct Response {
     refund_time: Option<DateTime<Utc>>,
     refund_request_id: Option<String>,
     refund_id: Option<String>,
+    refund_status: Option<RefundStatus>,
     payment_status: Option<PaymentStatus>,
     payment_result_code: Option<String>,
     payment_result_message: Option<String>,
@@ -494,6 +665,9 @@ impl Response {
     pub fn get_refund_id(&self) -> &Option<String> {
         &self.refund_id
     }
+    pub fn get_refund_status(&self) -> &Option<RefundStatus> {
+        &self.refund_status
+    }
     pub fn get_payment_status(&self) -> &Option<PaymentStatus> {
         &self.payment_status
     }
@@ -534,21 +708,16 @@ impl ResponseResult {
         if self.result_code == ResultCode::PAYMENT_IN_PROCESS {
             // payment in process will have a result status code as U
             return None;
-        } else if self.result_code == ResultCode::UNKNOWN_EXCEPTION {
-            // First of all, this result code gives no information.
-            // When you encounter this error, you should just retry,
-            // this happens alot in sandbox environment.
-            return Some(Error::Unknown(format!("{}: {}", self.result_code.to_string(), "You should just retry. This error is very normal due to unstable service of Alipay Global. This could happen even when you have all the argument correct. Just retry.")));
         }
         match self.result_status {
             ResultStatus::S => None,
-            ResultStatus::F => Some(Error::Fail(self.result_code.to_string())),
-            ResultStatus::U => Some(Error::Unknown(self.result_code.to_string())),
+            _ => Some(Error::from(self.clone())),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Display, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Display, EnumString, Clone)]
+#[allow(non_camel_case_types)]
 pub enum ResultCode {
     SUCCESS,
     ACCESS_DENIED,
@@ -597,17 +766,26 @@ pub enum ResultCode {
     REFUND_WINDOW_EXCEED,
     REFUND_IN_PROCESS,
     REFUND_NOT_SUPPORTED,
+    REFUND_NOT_EXIST,
+    /// A value this version does not know yet, kept as Alipay sent it
+    #[strum(default)]
+    #[serde(untagged)]
+    Other(String),
 }
 
 /// Result status. Valid values are:
 /// S: Indicates that the API call succeeds.
 /// F: Indicates that the API call fails.
 /// U: Indicates that the API call might be successful, in process, or failed. For more details, see Result process logic.
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Display, EnumString)]
 pub enum ResultStatus {
     S,
     F,
     U,
+    /// A value this version does not know yet, kept as Alipay sent it
+    #[strum(default)]
+    #[serde(untagged)]
+    Other(String),
 }
 /// Information about the order code.
 /// This parameter is returned when the payment method supports providing the related information.  
@@ -639,13 +817,16 @@ pub struct PspCustomerInfo {
     display_customer_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "juniper", derive(GraphQLEnum))]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Display, EnumString)]
 pub enum DisplayType {
     TEXT,
     MIDDLEIMAGE,
     SMALLIMAGE,
     BIGIMAGE,
+    /// A value this version does not know yet, kept as Alipay sent it
+    #[strum(default)]
+    #[serde(untagged)]
+    Other(String),
 }
 
 /// The exchange rate between the settlement currency and transaction currency. This field is returned when grossSettlementAmount is returned.
@@ -670,13 +851,45 @@ pub struct PaymentResultInfo {
     network_transaction_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Display, EnumString)]
 pub enum PaymentStatus {
     SUCCESS,
     FAIL,
     PROCESSING,
     CANCELLED,
     PENDING,
+    /// A value this version does not know yet, kept as Alipay sent it
+    #[strum(default)]
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl PaymentStatus {
+    /// Whether the payment can no longer change
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            PaymentStatus::SUCCESS | PaymentStatus::FAIL | PaymentStatus::CANCELLED
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Display, EnumString)]
+pub enum RefundStatus {
+    SUCCESS,
+    FAIL,
+    PROCESSING,
+    /// A value this version does not know yet, kept as Alipay sent it
+    #[strum(default)]
+    #[serde(untagged)]
+    Other(String),
+}
+
+impl RefundStatus {
+    /// Whether the refund can no longer change
+    pub fn is_final(&self) -> bool {
+        matches!(self, RefundStatus::SUCCESS | RefundStatus::FAIL)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -688,10 +901,14 @@ pub struct RedirectActionForm {
     action_form_type: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Display, EnumString)]
 pub enum RedirectActionFormMethod {
     POST,
     GET,
+    /// A value this version does not know yet, kept as Alipay sent it
+    #[strum(default)]
+    #[serde(untagged)]
+    Other(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -741,9 +958,9 @@ pub struct CashierPaymentRefundFull {
     refund_amount: RefundAmount,
 }
 
-impl CashierPaymentRefundFull {
-    pub fn to_string(&self) -> String {
-        serde_json::to_value(self).unwrap().to_string()
+impl std::fmt::Display for CashierPaymentRefundFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_value())
     }
 }
 
@@ -758,7 +975,7 @@ impl From<&CashierPaymentRefundSimple> for CashierPaymentRefundFull {
         Self {
             payment_id: payment_id.clone(),
             refund_request_id: refund_request_id.clone(),
-            refund_amount: refund_amount,
+            refund_amount,
         }
     }
 }
@@ -769,6 +986,12 @@ impl Signable for CashierPaymentRefundFull {
     }
 }
 
+impl HasEndpoint for CashierPaymentRefundFull {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Refund
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CashierPaymentInquiry {
@@ -776,9 +999,9 @@ pub struct CashierPaymentInquiry {
     pub payment_id: Option<String>,
 }
 
-impl CashierPaymentInquiry {
-    pub fn to_string(&self) -> String {
-        serde_json::to_value(self).unwrap().to_string()
+impl std::fmt::Display for CashierPaymentInquiry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_value())
     }
 }
 
@@ -788,22 +1011,44 @@ impl Signable for CashierPaymentInquiry {
     }
 }
 
+impl HasEndpoint for CashierPaymentInquiry {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::InquiryPayment
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CashierPaymentRefundInquiry {
+    pub refund_request_id: Option<String>,
+    pub refund_id: Option<String>,
+}
+
+impl std::fmt::Display for CashierPaymentRefundInquiry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_value())
+    }
+}
+
+impl Signable for CashierPaymentRefundInquiry {
+    fn get_value(&self) -> Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+impl HasEndpoint for CashierPaymentRefundInquiry {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::InquiryRefund
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PaymentAmount {
     currency: String,
     value: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct NotifyPayment {
-    capture_amount: PaymentAmount,
-    notify_type: String,
-    capture_id: String,
-    capture_request_id: String,
-    capture_time: String,
-    payment_id: String,
-    result: ResponseResult,
-}
+pub use super::notification::NotifyPayment;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WebhookData {
@@ -841,3 +1086,110 @@ impl Signable for WebhookResponseResult {
         serde_json::to_value(self).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{fixture_path, merchant_secret};
+
+    #[test]
+    fn private_key_and_passphrase_are_not_written_out() {
+        let mut secret = merchant_secret().with_private_key_pem("PRIVATE KEY BODY");
+        secret.private_key_passphrase = Some(String::from("hunter2"));
+
+        let json = serde_json::to_string(&secret).unwrap();
+        assert!(!json.contains("PRIVATE KEY BODY") && !json.contains("hunter2"));
+        let restored: AlipayClientSecret = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.private_key_pem, None);
+
+        let debug = format!("{:?}", secret);
+        assert!(!debug.contains("PRIVATE KEY BODY") && !debug.contains("hunter2"));
+        assert!(debug.contains("<redacted>"));
+    }
+
+    #[test]
+    fn new_secrets_take_the_environment_of_the_client_id() {
+        let secret = AlipayClientSecret::new("SANDBOX_TEST");
+        assert!(secret.sandbox);
+        assert!(!secret.live_payments_allowed);
+        assert!(matches!(
+            secret.get_private_key(),
+            Err(KeyError::Missing(Key::MerchantPrivate))
+        ));
+        assert!(!AlipayClientSecret::new("2188120000000000").sandbox);
+    }
+
+    #[test]
+    fn loads_encrypted_private_key_with_passphrase() {
+        let mut secret = merchant_secret();
+        secret.private_key_pem_file =
+            Some(Box::new(fixture_path("merchant_private_key_encrypted.pem")));
+        assert!(matches!(
+            secret.get_private_key(),
+            Err(KeyError::PassphraseRequired(Key::MerchantPrivate))
+        ));
+        secret.private_key_passphrase = Some(String::from("correct-horse"));
+        assert!(secret.get_private_key().is_ok());
+    }
+
+    #[test]
+    fn key_failures_are_errors_naming_the_key() {
+        let mut secret = merchant_secret();
+        secret.private_key_pem_file = None;
+        assert!(matches!(
+            secret.get_private_key(),
+            Err(KeyError::Missing(Key::MerchantPrivate))
+        ));
+
+        let mut secret = merchant_secret();
+        secret.private_key_pem_file = Some(Box::new(PathBuf::from("/nonexistent/key.pem")));
+        let e = secret.get_private_key().unwrap_err();
+        assert!(matches!(e, KeyError::Read { .. }));
+        assert!(e.to_string().contains("/nonexistent/key.pem"));
+
+        let mut secret = merchant_secret();
+        secret.alipay_public_key_pem_file = None;
+        secret.alipay_public_key_pem = Some(String::from("not a key"));
+        let e = crate::sansio::AlipayProtocol::new(&secret).err().unwrap();
+        assert!(matches!(
+            e,
+            Error::Key(KeyError::Invalid {
+                key: Key::AlipayPublic,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_values_are_kept_instead_of_failing_the_response() {
+        let response: Response = serde_json::from_value(json!({
+            "result": {"resultCode": "NEW_CODE", "resultStatus": "X", "resultMessage": "new"},
+            "paymentStatus": "AUTHORIZED",
+            "refundStatus": "SUCCESS",
+            "redirectActionForm": {"method": "PUT", "redirectUrl": "https://example.com"}
+        }))
+        .unwrap();
+        let result = response.result();
+        assert_eq!(
+            result.result_code,
+            ResultCode::Other(String::from("NEW_CODE"))
+        );
+        assert_eq!(result.result_status, ResultStatus::Other(String::from("X")));
+        assert_eq!(
+            response.get_payment_status(),
+            &Some(PaymentStatus::Other(String::from("AUTHORIZED")))
+        );
+        assert_eq!(response.get_refund_status(), &Some(RefundStatus::SUCCESS));
+        assert_eq!(result.result_code.to_string(), "NEW_CODE");
+        assert_eq!(
+            serde_json::to_value(&result.result_code).unwrap(),
+            json!("NEW_CODE")
+        );
+        assert_eq!(
+            "BIGIMAGE".parse::<DisplayType>().unwrap(),
+            DisplayType::BIGIMAGE
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! The notifications Alipay sends to the merchant.
+//!
+//! Payment, capture, refund and vaulting notifications are told apart by their
+//! `notifyType`. Authorization, dispute and subscription notifications carry their
+//! own type field instead, and declaration notifications a `declarationRequestId`.
+//! see: https://global.alipay.com/docs/ac/ams/notifications
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::errors::Error;
+use super::models::{Amount, PaymentStatus, RefundStatus, ResponseResult};
+
+/// The result of a payment, see notifyPayment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyPayment {
+    /// `PAYMENT_RESULT`, or `PAYMENT_PENDING` while the payment is processed
+    pub notify_type: String,
+    pub result: ResponseResult,
+    pub payment_request_id: String,
+    pub payment_id: String,
+    pub payment_amount: Amount,
+    pub payment_status: Option<PaymentStatus>,
+    pub payment_create_time: Option<DateTime<Utc>>,
+    pub payment_time: Option<DateTime<Utc>>,
+    pub customs_declaration_amount: Option<Amount>,
+    pub gross_settlement_amount: Option<Amount>,
+    pub acquirer_reference_no: Option<String>,
+}
+
+/// The result of a capture, see notifyCapture
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyCapture {
+    /// `CAPTURE_RESULT`
+    pub notify_type: String,
+    pub result: ResponseResult,
+    pub capture_request_id: String,
+    pub capture_id: String,
+    pub payment_id: String,
+    pub capture_amount: Amount,
+    pub capture_time: Option<DateTime<Utc>>,
+    pub acquirer_reference_no: Option<String>,
+}
+
+/// The result of a refund, see notifyRefund
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyRefund {
+    /// `REFUND_RESULT`
+    pub notify_type: String,
+    pub result: ResponseResult,
+    pub refund_status: Option<RefundStatus>,
+    pub refund_request_id: String,
+    pub refund_id: String,
+    pub refund_amount: Option<Amount>,
+    pub refund_create_time: Option<DateTime<Utc>>,
+    pub refund_time: Option<DateTime<Utc>>,
+    pub gross_settlement_amount: Option<Amount>,
+}
+
+/// An authorization was granted or revoked by the user, see notifyAuthorization
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyAuthorization {
+    /// e.g. `AUTHCODE_CREATED` or `TOKEN_CANCELED`
+    pub authorization_notify_type: String,
+    pub auth_client_id: Option<String>,
+    pub access_token: Option<String>,
+    pub auth_state: Option<String>,
+    pub auth_code: Option<String>,
+    pub reason: Option<String>,
+    pub user_login_id: Option<String>,
+}
+
+/// A dispute was raised, judged or needs a defense, see notifyDispute
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyDispute {
+    /// e.g. `DISPUTE_CREATED`, `DISPUTE_JUDGED` or `DEFENSE_DUE_ALERT`
+    pub dispute_notification_type: String,
+    pub dispute_id: String,
+    pub payment_id: Option<String>,
+    pub payment_request_id: Option<String>,
+    pub dispute_time: Option<DateTime<Utc>>,
+    pub dispute_amount: Option<Amount>,
+    pub dispute_reason_code: Option<String>,
+    pub dispute_reason_msg: Option<String>,
+    pub dispute_judged_time: Option<DateTime<Utc>>,
+    pub dispute_judged_amount: Option<Amount>,
+    pub dispute_judged_result: Option<String>,
+    pub defense_due_time: Option<DateTime<Utc>>,
+    pub dispute_source: Option<String>,
+}
+
+/// A subscription changed state, see notifySubscription
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifySubscription {
+    /// e.g. `ACTIVE`, `TERMINATED` or `CANCELLED`
+    pub subscription_notification_type: String,
+    pub subscription_request_id: Option<String>,
+    pub subscription_id: Option<String>,
+    pub subscription_start_time: Option<DateTime<Utc>>,
+    pub subscription_end_time: Option<DateTime<Utc>>,
+}
+
+/// The customs declaration of a payment was processed, see notifyDeclaration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyDeclaration {
+    pub notify_type: Option<String>,
+    pub result: Option<ResponseResult>,
+    pub declaration_request_id: String,
+    pub payment_id: Option<String>,
+    pub customs_code: Option<String>,
+    pub declaration_amount: Option<Amount>,
+}
+
+/// A payment method was vaulted, see notifyVaulting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyVaulting {
+    /// `VAULTING_RESULT`
+    pub notify_type: String,
+    pub result: ResponseResult,
+    pub vaulting_request_id: String,
+    pub payment_method_detail: Option<Value>,
+    pub vaulting_create_time: Option<DateTime<Utc>>,
+}
+
+/// Which notification a delivery is, a handler is registered per kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NotificationKind {
+    Payment,
+    Capture,
+    Refund,
+    Authorization,
+    Dispute,
+    Subscription,
+    Declaration,
+    Vaulting,
+    Other,
+}
+
+/// A notification, parsed according to its type
+#[derive(Debug, Clone)]
+pub enum Notification {
+    Payment(NotifyPayment),
+    Capture(NotifyCapture),
+    Refund(NotifyRefund),
+    Authorization(NotifyAuthorization),
+    Dispute(NotifyDispute),
+    Subscription(NotifySubscription),
+    Declaration(NotifyDeclaration),
+    Vaulting(NotifyVaulting),
+    /// A notification this version does not know yet, kept as Alipay sent it
+    Other(Value),
+}
+
+impl Notification {
+    /// Parse a notification body, choosing the model by its type
+    pub fn parse(body: &str) -> Result<Self, Error> {
+        let value: Value = serde_json::from_str(body)?;
+        let kind = NotificationKind::of(&value);
+        Ok(match kind {
+            NotificationKind::Payment => Notification::Payment(from_value(value)?),
+            NotificationKind::Capture => Notification::Capture(from_value(value)?),
+            NotificationKind::Refund => Notification::Refund(from_value(value)?),
+            NotificationKind::Authorization => Notification::Authorization(from_value(value)?),
+            NotificationKind::Dispute => Notification::Dispute(from_value(value)?),
+            NotificationKind::Subscription => Notification::Subscription(from_value(value)?),
+            NotificationKind::Declaration => Notification::Declaration(from_value(value)?),
+            NotificationKind::Vaulting => Notification::Vaulting(from_value(value)?),
+            NotificationKind::Other => Notification::Other(value),
+        })
+    }
+
+    pub fn kind(&self) -> NotificationKind {
+        match self {
+            Notification::Payment(_) => NotificationKind::Payment,
+            Notification::Capture(_) => NotificationKind::Capture,
+            Notification::Refund(_) => NotificationKind::Refund,
+            Notification::Authorization(_) => NotificationKind::Authorization,
+            Notification::Dispute(_) => NotificationKind::Dispute,
+            Notification::Subscription(_) => NotificationKind::Subscription,
+            Notification::Declaration(_) => NotificationKind::Declaration,
+            Notification::Vaulting(_) => NotificationKind::Vaulting,
+            Notification::Other(_) => NotificationKind::Other,
+        }
+    }
+}
+
+impl NotificationKind {
+    fn of(value: &Value) -> Self {
+        let has = |field: &str| value.get(field).is_some();
+        match value.get("notifyType").and_then(Value::as_str) {
+            Some("PAYMENT_RESULT") | Some("PAYMENT_PENDING") => NotificationKind::Payment,
+            Some("CAPTURE_RESULT") => NotificationKind::Capture,
+            Some("REFUND_RESULT") => NotificationKind::Refund,
+            Some("VAULTING_RESULT") => NotificationKind::Vaulting,
+            _ if has("authorizationNotifyType") => NotificationKind::Authorization,
+            _ if has("disputeNotificationType") => NotificationKind::Dispute,
+            _ if has("subscriptionNotificationType") => NotificationKind::Subscription,
+            _ if has("declarationRequestId") => NotificationKind::Declaration,
+            _ => NotificationKind::Other,
+        }
+    }
+}
+
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    Ok(serde_json::from_value(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(value: Value) -> Notification {
+        Notification::parse(&value.to_string()).unwrap()
+    }
+
+    #[test]
+    fn parses_by_notify_type() {
+        let payment = parse(json!({
+            "notifyType": "PAYMENT_RESULT",
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+            "paymentRequestId": "order-1",
+            "paymentId": "2019060811401080010018882020035",
+            "paymentAmount": {"currency": "USD", "value": "100"},
+            "paymentCreateTime": "2019-06-01T12:01:01+08:30",
+            "paymentTime": "2019-06-01T12:01:01+08:30"
+        }));
+        let Notification::Payment(payment) = payment else {
+            panic!("expected a payment notification")
+        };
+        assert_eq!(payment.payment_request_id, "order-1");
+        assert_eq!(payment.payment_amount.value(), 100);
+
+        let refund = parse(json!({
+            "notifyType": "REFUND_RESULT",
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+            "refundStatus": "SUCCESS",
+            "refundRequestId": "refund-1",
+            "refundId": "r1",
+            "refundAmount": {"currency": "USD", "value": "100"}
+        }));
+        assert_eq!(refund.kind(), NotificationKind::Refund);
+
+        let capture = parse(json!({
+            "notifyType": "CAPTURE_RESULT",
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+            "captureRequestId": "capture-1",
+            "captureId": "c1",
+            "paymentId": "p1",
+            "captureAmount": {"currency": "USD", "value": "100"},
+            "captureTime": "2019-06-01T12:01:01+08:30"
+        }));
+        assert_eq!(capture.kind(), NotificationKind::Capture);
+    }
+
+    #[test]
+    fn parses_notifications_with_their_own_type_field() {
+        let dispute = parse(json!({
+            "disputeNotificationType": "DISPUTE_CREATED",
+            "disputeId": "d1",
+            "paymentId": "p1",
+            "disputeAmount": {"currency": "USD", "value": "100"}
+        }));
+        assert_eq!(dispute.kind(), NotificationKind::Dispute);
+
+        let authorization = parse(json!({
+            "authorizationNotifyType": "TOKEN_CANCELED",
+            "accessToken": "token"
+        }));
+        assert_eq!(authorization.kind(), NotificationKind::Authorization);
+
+        let unknown = parse(json!({"notifyType": "SOMETHING_NEW"}));
+        assert!(matches!(unknown, Notification::Other(_)));
+    }
+
+    #[test]
+    fn a_known_type_with_a_wrong_shape_is_an_error() {
+        let body = json!({"notifyType": "PAYMENT_RESULT"}).to_string();
+        assert!(matches!(Notification::parse(&body), Err(Error::Parse(_))));
+    }
+}
@@ -0,0 +1,604 @@
+//! Refuse stale and replayed notifications.
+//!
+//! With a clock skew set, a notification is only accepted when its `Request-Time`
+//! is within it. With a [`ReplayStore`], it is only accepted when it was not
+//! received before. A notification is recorded under its type and the id of what
+//! it is about, e.g. `PAYMENT_RESULT:2019...` or `DISPUTE_JUDGED:<disputeId>`, and
+//! kept until the clock skew check would refuse it anyway.
+//!
+//! Alipay delivers a notification again until it is acknowledged. When handling
+//! one fails, [`forget`](crate::sansio::AlipayProtocol::forget_webhook) it before
+//! answering with a failed acknowledgement, so the next delivery is accepted.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fs2::FileExt;
+use serde_json::Value;
+
+use super::errors::BoxError;
+
+/// The clock skew of [`WebhookPolicy::fresh`]
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// How many keys a store keeps by default
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+/// Remembers the notifications already received
+pub trait ReplayStore: Send + Sync {
+    /// Record `key` for `keep`, `None` for as long as there is room.
+    /// `false` if it is recorded already.
+    fn record(&self, key: &str, keep: Option<Duration>) -> Result<bool, BoxError>;
+
+    /// Forget `key`, so the next delivery is accepted again
+    fn forget(&self, key: &str) -> Result<(), BoxError>;
+}
+
+/// How notifications are checked once their signature is verified.
+/// The default checks nothing, like before replay protection existed.
+///
+/// ```
+/// use std::sync::Arc;
+/// use alipay_global::replay::{MemoryReplayStore, WebhookPolicy};
+///
+/// let policy = WebhookPolicy {
+///     replay_store: Some(Arc::new(MemoryReplayStore::default())),
+///     ..WebhookPolicy::fresh()
+/// };
+/// ```
+#[derive(Clone, Default)]
+pub struct WebhookPolicy {
+    /// How far `Request-Time` may be from now, `None` to accept any time
+    pub max_clock_skew: Option<Duration>,
+    /// Where received notifications are recorded, `None` to accept replays
+    pub replay_store: Option<Arc<dyn ReplayStore>>,
+}
+
+impl WebhookPolicy {
+    /// Refuse notifications sent more than [`DEFAULT_MAX_CLOCK_SKEW`] from now
+    pub fn fresh() -> Self {
+        Self {
+            max_clock_skew: Some(DEFAULT_MAX_CLOCK_SKEW),
+            replay_store: None,
+        }
+    }
+}
+
+/// The key a notification body is recorded under, `None` without any id to tell it by
+pub(crate) fn replay_key(body: &str) -> Option<String> {
+    let body: Value = serde_json::from_str(body).ok()?;
+    let field = |names: &[&str]| names.iter().find_map(|name| body.get(*name)?.as_str());
+    let id = field(&[
+        "refundId",
+        "captureId",
+        "disputeId",
+        "subscriptionId",
+        "declarationRequestId",
+        "vaultingRequestId",
+        "paymentId",
+    ])?;
+    let notify_type = field(&[
+        "notifyType",
+        "disputeNotificationType",
+        "subscriptionNotificationType",
+        "authorizationNotifyType",
+    ])
+    .unwrap_or("");
+    Some(format!("{}:{}", notify_type, id))
+}
+
+/// The recorded keys, dropped once they expire or to make room for new ones
+#[derive(Debug)]
+struct Seen {
+    entries: HashMap<String, Entry>,
+    /// The keys by the time they were recorded, the first one is evicted when full
+    by_age: BTreeSet<(SystemTime, String)>,
+    /// The keys that expire, by the time they do
+    by_expiry: BTreeSet<(SystemTime, String)>,
+    capacity: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    recorded: SystemTime,
+    expires: Option<SystemTime>,
+}
+
+impl Seen {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_age: BTreeSet::new(),
+            by_expiry: BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    /// Record `key` at `now`, evicting the oldest key when full.
+    /// `None` if it is recorded already, else whether a key was evicted.
+    fn record(&mut self, key: &str, keep: Option<Duration>, now: SystemTime) -> Option<bool> {
+        if self.entries.contains_key(key) {
+            return None;
+        }
+        let expires = keep.and_then(|keep| now.checked_add(keep));
+        Some(self.insert(
+            key.to_string(),
+            Entry {
+                recorded: now,
+                expires,
+            },
+        ))
+    }
+
+    /// Insert or replace `key`, evicting the oldest key when full. Whether a key was evicted.
+    fn insert(&mut self, key: String, entry: Entry) -> bool {
+        self.remove(&key);
+        let evicted = self.entries.len() >= self.capacity.max(1);
+        if evicted {
+            if let Some((_, oldest)) = self.by_age.first().cloned() {
+                self.remove(&oldest);
+            }
+        }
+        self.by_age.insert((entry.recorded, key.clone()));
+        if let Some(expires) = entry.expires {
+            self.by_expiry.insert((expires, key.clone()));
+        }
+        self.entries.insert(key, entry);
+        evicted
+    }
+
+    /// `true` if `key` was recorded
+    fn remove(&mut self, key: &str) -> bool {
+        let Some(entry) = self.entries.remove(key) else {
+            return false;
+        };
+        self.by_age.remove(&(entry.recorded, key.to_string()));
+        if let Some(expires) = entry.expires {
+            self.by_expiry.remove(&(expires, key.to_string()));
+        }
+        true
+    }
+
+    /// Drop the keys expired at `now`
+    fn expire(&mut self, now: SystemTime) {
+        while let Some((expires, key)) = self.by_expiry.first().cloned() {
+            if expires > now {
+                break;
+            }
+            self.remove(&key);
+        }
+    }
+}
+
+/// Keeps the keys in memory, they are lost on restart
+#[derive(Debug)]
+pub struct MemoryReplayStore {
+    seen: Mutex<Seen>,
+}
+
+impl MemoryReplayStore {
+    /// Keep at most `capacity` keys, the oldest is dropped to make room
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            seen: Mutex::new(Seen::new(capacity)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entries
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for MemoryReplayStore {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl ReplayStore for MemoryReplayStore {
+    fn record(&self, key: &str, keep: Option<Duration>) -> Result<bool, BoxError> {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = SystemTime::now();
+        seen.expire(now);
+        Ok(seen.record(key, keep, now).is_some())
+    }
+
+    fn forget(&self, key: &str) -> Result<(), BoxError> {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        seen.remove(key);
+        Ok(())
+    }
+}
+
+/// Keeps the keys in a file so they survive a restart.
+///
+/// The file is a log: records and forgets are appended, and the keys are kept in
+/// memory. Every record and forget holds an exclusive lock on a `.lock` file next
+/// to it and first reads what other processes appended since, so several processes
+/// can share the file. The log is compacted once most of its lines are obsolete.
+#[derive(Debug)]
+pub struct FileReplayStore {
+    path: PathBuf,
+    index: Mutex<Index>,
+}
+
+/// The keys of the file, and how much of the file they reflect
+#[derive(Debug)]
+struct Index {
+    seen: Seen,
+    /// The header of the file read, a compaction by another process changes it
+    generation: String,
+    /// Bytes of the file read so far
+    read: u64,
+    /// Lines in the file, obsolete ones included
+    lines: usize,
+}
+
+impl FileReplayStore {
+    /// Open the file, creating it if it does not exist
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        File::options().append(true).create(true).open(&path)?;
+        Ok(Self {
+            path,
+            index: Mutex::new(Index {
+                seen: Seen::new(DEFAULT_CAPACITY),
+                generation: String::new(),
+                read: 0,
+                lines: 0,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keep at most `capacity` keys, the oldest is dropped to make room
+    pub fn set_capacity(&mut self, capacity: usize) {
+        let index = self.index.get_mut().unwrap_or_else(PoisonError::into_inner);
+        index.seen.capacity = capacity;
+    }
+
+    /// Run `f` on the keys, brought up to date with the file, while holding the lock
+    fn locked<T>(
+        &self,
+        f: impl FnOnce(&mut Index, &mut File) -> std::io::Result<T>,
+    ) -> Result<T, BoxError> {
+        let mut index = self.index.lock().unwrap_or_else(PoisonError::into_inner);
+        let lock = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.path.with_extension("lock"))?;
+        lock.lock_exclusive()?;
+        let mut file = File::options()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        if let Err(e) = index.catch_up(&mut file) {
+            // start over from the whole file next time
+            index.read = 0;
+            return Err(e.into());
+        }
+        let result = f(&mut index, &mut file)?;
+        if index.lines > COMPACT_MIN_LINES && index.lines > 2 * index.seen.entries.len() {
+            self.compact(&mut index)?;
+        }
+        Ok(result)
+    }
+
+    /// Replace the file with the keys in memory, swapping a new file in
+    fn compact(&self, index: &mut Index) -> std::io::Result<()> {
+        let generation = format!("# {:x}", rand::random::<u64>());
+        let swap = self.path.with_extension("swap");
+        let mut file = BufWriter::new(File::create(&swap)?);
+        writeln!(file, "{}", generation)?;
+        let mut lines = 1;
+        for (_, key) in &index.seen.by_age {
+            writeln!(file, "{}", format_line(key, &index.seen.entries[key]))?;
+            lines += 1;
+        }
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_data()?;
+        let read = file.metadata()?.len();
+        std::fs::rename(&swap, &self.path)?;
+        index.generation = generation;
+        index.read = read;
+        index.lines = lines;
+        Ok(())
+    }
+}
+
+/// A log is compacted only once it has at least this many lines
+const COMPACT_MIN_LINES: usize = 1024;
+
+impl Index {
+    /// Apply what was appended since the last read, or read everything again after a compaction
+    fn catch_up(&mut self, file: &mut File) -> std::io::Result<()> {
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(&*file);
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_end().to_string();
+        if header != self.generation || len < self.read {
+            self.seen = Seen::new(self.seen.capacity);
+            self.generation = header;
+            self.read = 0;
+            self.lines = 0;
+        }
+        if self.read == 0 {
+            reader.seek(SeekFrom::Start(0))?;
+        } else {
+            reader.seek(SeekFrom::Start(self.read))?;
+        }
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            if !line.ends_with('\n') {
+                // a line still being written, read it again next time
+                break;
+            }
+            self.read += line.len() as u64;
+            self.lines += 1;
+            match parse_line(line.trim_end())? {
+                Line::Empty => {}
+                Line::Record(key, entry) => {
+                    self.seen.insert(key, entry);
+                }
+                Line::Forget(key) => {
+                    self.seen.remove(&key);
+                }
+            }
+            line.clear();
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, file: &mut File, line: &str) -> std::io::Result<()> {
+        let line = format!("{}\n", line);
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.read += line.len() as u64;
+        self.lines += 1;
+        Ok(())
+    }
+}
+
+impl ReplayStore for FileReplayStore {
+    fn record(&self, key: &str, keep: Option<Duration>) -> Result<bool, BoxError> {
+        self.locked(|index, file| {
+            let now = SystemTime::now();
+            index.seen.expire(now);
+            if index.seen.record(key, keep, now).is_none() {
+                return Ok(false);
+            }
+            let line = format_line(key, &index.seen.entries[key]);
+            index.append(file, &line)?;
+            Ok(true)
+        })
+    }
+
+    fn forget(&self, key: &str) -> Result<(), BoxError> {
+        self.locked(|index, file| {
+            if index.seen.remove(key) {
+                index.append(file, &format!("- - {}", key))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A line of the log
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    /// Blank lines, and the `#` header a compaction starts the file with
+    Empty,
+    Record(String, Entry),
+    Forget(String),
+}
+
+/// `<recorded> <expires or -> <key>`, times in seconds since the epoch.
+/// `- - <key>` forgets the key.
+fn format_line(key: &str, entry: &Entry) -> String {
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    };
+    let expires = match entry.expires {
+        Some(expires) => secs(expires).to_string(),
+        None => String::from("-"),
+    };
+    format!("{} {} {}", secs(entry.recorded), expires, key)
+}
+
+fn parse_line(line: &str) -> std::io::Result<Line> {
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Line::Empty);
+    }
+    let invalid =
+        || std::io::Error::new(ErrorKind::InvalidData, format!("invalid line {:?}", line));
+    let mut fields = line.splitn(3, ' ');
+    let mut time = || -> std::io::Result<Option<SystemTime>> {
+        match fields.next().ok_or_else(invalid)? {
+            "-" => Ok(None),
+            secs => {
+                let secs = secs.parse().map_err(|_| invalid())?;
+                Ok(Some(UNIX_EPOCH + Duration::from_secs(secs)))
+            }
+        }
+    };
+    let recorded = time()?;
+    let expires = time()?;
+    let key = fields.next().ok_or_else(invalid)?.to_string();
+    match (recorded, expires) {
+        (Some(recorded), expires) => Ok(Line::Record(key, Entry { recorded, expires })),
+        (None, None) => Ok(Line::Forget(key)),
+        (None, Some(_)) => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_by_notify_type_and_id() {
+        assert_eq!(
+            replay_key(r#"{"notifyType":"PAYMENT_RESULT","paymentId":"p1"}"#).as_deref(),
+            Some("PAYMENT_RESULT:p1")
+        );
+        assert_eq!(
+            replay_key(r#"{"notifyType":"REFUND_RESULT","paymentId":"p1","refundId":"r1"}"#)
+                .as_deref(),
+            Some("REFUND_RESULT:r1")
+        );
+        assert_eq!(
+            replay_key(
+                r#"{"disputeNotificationType":"DISPUTE_JUDGED","paymentId":"p1","disputeId":"d1"}"#
+            )
+            .as_deref(),
+            Some("DISPUTE_JUDGED:d1")
+        );
+        assert_eq!(replay_key(r#"{"notifyType":"PAYMENT_RESULT"}"#), None);
+    }
+
+    #[test]
+    fn file_store_survives_a_restart() {
+        let dir = std::env::temp_dir().join(format!("alipay-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notifications");
+        let _ = std::fs::remove_file(&path);
+
+        let store = FileReplayStore::open(&path).unwrap();
+        assert!(store.record("PAYMENT_RESULT:p1", None).unwrap());
+        assert!(store.record("PAYMENT_RESULT:p2", None).unwrap());
+        assert!(!store.record("PAYMENT_RESULT:p1", None).unwrap());
+        store.forget("PAYMENT_RESULT:p2").unwrap();
+        assert!(store
+            .record("REFUND_RESULT:r1", Some(Duration::ZERO))
+            .unwrap());
+
+        let reopened = FileReplayStore::open(&path).unwrap();
+        assert!(!reopened.record("PAYMENT_RESULT:p1", None).unwrap());
+        assert!(reopened.record("PAYMENT_RESULT:p2", None).unwrap());
+        // expired, its delivery would be stale by now
+        assert!(reopened.record("REFUND_RESULT:r1", None).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_store_appends_and_compacts() {
+        let dir = std::env::temp_dir().join(format!("alipay-replay-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notifications");
+        let _ = std::fs::remove_file(&path);
+
+        let first = FileReplayStore::open(&path).unwrap();
+        let second = FileReplayStore::open(&path).unwrap();
+        assert!(first.record("PAYMENT_RESULT:p1", None).unwrap());
+        assert!(!second.record("PAYMENT_RESULT:p1", None).unwrap());
+        second.forget("PAYMENT_RESULT:p1").unwrap();
+        assert!(first.record("PAYMENT_RESULT:p1", None).unwrap());
+
+        // records and forgets pile up until the log is compacted
+        for i in 0..COMPACT_MIN_LINES {
+            let key = format!("PAYMENT_RESULT:q{}", i);
+            assert!(first.record(&key, None).unwrap());
+            first.forget(&key).unwrap();
+        }
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= COMPACT_MIN_LINES + 1, "{} lines", lines);
+        // the other store reads the compacted file from the start
+        assert!(!second.record("PAYMENT_RESULT:p1", None).unwrap());
+        assert!(second.record("PAYMENT_RESULT:q0", None).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_store_is_shared_between_workers() {
+        let dir = std::env::temp_dir().join(format!("alipay-replay-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notifications");
+        let _ = std::fs::remove_file(&path);
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = FileReplayStore::open(&path).unwrap();
+                std::thread::spawn(move || {
+                    (0..20)
+                        .filter(|i| {
+                            store
+                                .record(&format!("PAYMENT_RESULT:p{}", i), None)
+                                .unwrap()
+                        })
+                        .count()
+                })
+            })
+            .collect();
+        let accepted: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+        // every notification was accepted by exactly one worker
+        assert_eq!(accepted, 20);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn memory_store_expires_and_is_capped() {
+        let store = MemoryReplayStore::with_capacity(2);
+        assert!(store
+            .record("PAYMENT_RESULT:p1", Some(Duration::ZERO))
+            .unwrap());
+        assert!(store.record("PAYMENT_RESULT:p1", None).unwrap());
+        assert!(!store.record("PAYMENT_RESULT:p1", None).unwrap());
+
+        assert!(store.record("PAYMENT_RESULT:p2", None).unwrap());
+        assert!(store.record("PAYMENT_RESULT:p3", None).unwrap());
+        assert_eq!(store.len(), 2);
+        // p1 was the oldest, it made room for p3
+        assert!(!store.record("PAYMENT_RESULT:p3", None).unwrap());
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn evicts_the_oldest_key() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut seen = Seen::new(2);
+        assert_eq!(seen.record("a", None, start), Some(false));
+        assert_eq!(
+            seen.record("b", None, start + Duration::from_secs(1)),
+            Some(false)
+        );
+        assert_eq!(seen.record("a", None, start + Duration::from_secs(2)), None);
+        assert_eq!(
+            seen.record("c", None, start + Duration::from_secs(2)),
+            Some(true)
+        );
+        assert!(!seen.entries.contains_key("a"));
+
+        let entry = Entry {
+            recorded: start,
+            expires: Some(start + Duration::from_secs(300)),
+        };
+        assert_eq!(
+            parse_line(&format_line("k:1 2", &entry)).unwrap(),
+            Line::Record(String::from("k:1 2"), entry)
+        );
+        assert_eq!(
+            parse_line("- - k:1").unwrap(),
+            Line::Forget(String::from("k:1"))
+        );
+    }
+}
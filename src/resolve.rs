@@ -0,0 +1,440 @@
+//! Follow Alipay's [result process logic](https://global.alipay.com/docs/ac/ams/api_fund#ML5ur)
+//! until a payment or a refund has a definitive outcome.
+//!
+//! A `U` result status, a timeout or a lost response says nothing about whether
+//! the money moved. The `*_and_resolve` methods of the clients then inquire the
+//! request with backoff until Alipay reports a final status or the deadline passes.
+//! Errors that leave nothing to inquire, e.g. a request that could not be signed,
+//! end the resolution right away.
+//!
+//! A payment answered with `PAYMENT_IN_PROCESS` is not inquired: the buyer still
+//! has to pay on the cashier page, so it is returned as [`Outcome::Processing`]
+//! with the URL to redirect to.
+
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use super::endpoint::Endpoint;
+use super::errors::Error;
+use super::models::{
+    CashierPaymentFull, CashierPaymentInquiry, CashierPaymentRefundFull,
+    CashierPaymentRefundInquiry, CashierPaymentRefundSimple, CashierPaymentSimple, PaymentStatus,
+    RefundStatus, Response, ResultCode, ResultStatus, Signable,
+};
+use super::response::check_response;
+
+/// What finally happened to a payment or a refund
+#[derive(Debug)]
+pub enum Outcome {
+    /// Alipay confirmed the payment or refund succeeded
+    Succeeded(Response),
+    /// Alipay confirmed the payment or refund failed, or it never reached Alipay
+    Failed(Response),
+    /// Alipay accepted the request but it is not final yet, e.g. the buyer still has
+    /// to pay on the cashier page. Wait for the notification or inquire again later.
+    Processing(Response),
+    /// No definitive answer came back before the deadline, or an inquiry failed
+    /// in a way inquiring again cannot fix
+    Unknown(Error),
+    /// The payment or refund was not created: the request could not be signed, or Alipay
+    /// or the network refused it before it was processed. Nothing was inquired.
+    Error(Error),
+}
+
+impl Outcome {
+    /// The last verified response, if any
+    pub fn response(&self) -> Option<&Response> {
+        match self {
+            Outcome::Succeeded(r) | Outcome::Failed(r) | Outcome::Processing(r) => Some(r),
+            Outcome::Unknown(_) | Outcome::Error(_) => None,
+        }
+    }
+
+    pub fn is_succeeded(&self) -> bool {
+        matches!(self, Outcome::Succeeded(_))
+    }
+}
+
+/// How long and how often an undetermined request is inquired
+///
+/// ```
+/// use std::time::Duration;
+/// use alipay_global::resolve::ResolvePolicy;
+///
+/// let policy = ResolvePolicy {
+///     timeout: Duration::from_secs(10),
+///     ..ResolvePolicy::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvePolicy {
+    /// Give up inquiring once this much time has passed since the first request
+    pub timeout: Duration,
+    /// Delay before the first inquiry
+    pub initial_interval: Duration,
+    /// Upper bound of the delay between two inquiries
+    pub max_interval: Duration,
+    /// Factor the delay grows by after every inquiry
+    pub multiplier: f64,
+}
+
+impl Default for ResolvePolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            initial_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(10),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl ResolvePolicy {
+    /// The delay before inquiry `poll`, `poll` starting at 1.
+    /// A negative or non finite delay, from a bad `multiplier`, is `max_interval`.
+    pub fn interval(&self, poll: u32) -> Duration {
+        let exponent = poll.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = self.initial_interval.as_secs_f64() * self.multiplier.powi(exponent);
+        Duration::try_from_secs_f64(base.min(self.max_interval.as_secs_f64()))
+            .unwrap_or(self.max_interval)
+    }
+
+    fn start(&self) -> Polls<'_> {
+        Polls {
+            policy: self,
+            deadline: Instant::now() + self.timeout,
+            poll: 0,
+        }
+    }
+}
+
+/// One payment or refund being resolved, without I/O.
+///
+/// It tells the client which request to send and, from what came back, either the
+/// outcome or how long to wait before inquiring. The clients only send and sleep.
+pub(crate) struct Resolution<'a> {
+    polls: Polls<'a>,
+    request: Option<(Endpoint, RequestBody)>,
+    inquiry: (Endpoint, RequestBody),
+    classify: fn(Result<Response, Error>) -> Step,
+    after_inquiry: fn(Result<Response, Error>) -> Step,
+}
+
+/// A request body already turned into JSON
+#[derive(Clone)]
+pub(crate) struct RequestBody(pub(crate) Value);
+
+impl Signable for RequestBody {
+    fn get_value(&self) -> Value {
+        self.0.clone()
+    }
+}
+
+impl<'a> Resolution<'a> {
+    /// Create a payment, then inquire it by its `paymentRequestId`
+    pub(crate) fn payment(
+        cashier_payment: &CashierPaymentSimple,
+        policy: &'a ResolvePolicy,
+    ) -> Self {
+        let inquiry = CashierPaymentInquiry {
+            payment_request_id: Some(cashier_payment.payment_request_id.clone()),
+            payment_id: None,
+        };
+        Self {
+            polls: policy.start(),
+            request: Some((
+                Endpoint::Pay,
+                RequestBody(CashierPaymentFull::from(cashier_payment).get_value()),
+            )),
+            inquiry: (Endpoint::InquiryPayment, RequestBody(inquiry.get_value())),
+            classify: after_pay,
+            after_inquiry: after_payment_inquiry,
+        }
+    }
+
+    /// Refund a payment, then inquire the refund by its `refundRequestId`
+    pub(crate) fn refund(
+        cashier_payment_refund: &CashierPaymentRefundSimple,
+        policy: &'a ResolvePolicy,
+    ) -> Self {
+        let inquiry = CashierPaymentRefundInquiry {
+            refund_request_id: Some(cashier_payment_refund.refund_request_id.clone()),
+            refund_id: None,
+        };
+        Self {
+            polls: policy.start(),
+            request: Some((
+                Endpoint::Refund,
+                RequestBody(CashierPaymentRefundFull::from(cashier_payment_refund).get_value()),
+            )),
+            inquiry: (Endpoint::InquiryRefund, RequestBody(inquiry.get_value())),
+            classify: after_refund,
+            after_inquiry: after_refund_inquiry,
+        }
+    }
+
+    /// The request to send now: the payment or refund first, inquiries after it
+    pub(crate) fn request(&self) -> (Endpoint, &RequestBody) {
+        let (endpoint, body) = self.request.as_ref().unwrap_or(&self.inquiry);
+        (*endpoint, body)
+    }
+
+    /// Classify what the last request got back: either the outcome, or how long
+    /// to wait before sending the next [`request`](Self::request)
+    pub(crate) fn next(&mut self, sent: Result<Response, Error>) -> ControlFlow<Outcome, Duration> {
+        let step = (self.classify)(sent);
+        self.request = None;
+        self.classify = self.after_inquiry;
+        match step {
+            Step::Done(outcome) => ControlFlow::Break(outcome),
+            Step::Pending(last) => match self.polls.next_wait() {
+                Some(wait) => ControlFlow::Continue(wait),
+                None => ControlFlow::Break(Step::timed_out(last)),
+            },
+        }
+    }
+}
+
+/// Paces the inquiries of one resolution against its deadline
+struct Polls<'a> {
+    policy: &'a ResolvePolicy,
+    deadline: Instant,
+    poll: u32,
+}
+
+impl Polls<'_> {
+    /// How long to wait before the next inquiry, never past the deadline.
+    /// `None` once the deadline has passed.
+    fn next_wait(&mut self) -> Option<Duration> {
+        let left = self.deadline.checked_duration_since(Instant::now())?;
+        if left.is_zero() {
+            return None;
+        }
+        self.poll += 1;
+        Some(self.policy.interval(self.poll).min(left))
+    }
+}
+
+/// Where a resolution stands after one response
+enum Step {
+    Done(Outcome),
+    /// Inquire again. `Ok` holds a response that is known to be in process.
+    Pending(Result<Response, Error>),
+}
+
+impl Step {
+    /// The outcome when the deadline passes at this step
+    fn timed_out(last: Result<Response, Error>) -> Outcome {
+        match last {
+            Ok(response) => Outcome::Processing(response),
+            Err(e) => Outcome::Unknown(e),
+        }
+    }
+}
+
+/// An error leaves the outcome open only if it says so, any other error is final
+fn failed(e: Error, outcome: fn(Error) -> Outcome) -> Step {
+    if e.requires_inquiry() {
+        Step::Pending(Err(e))
+    } else {
+        Step::Done(outcome(e))
+    }
+}
+
+/// A create call that may have reached Alipay leaves the outcome open. Besides errors that
+/// say so, a 5xx may come from a gateway that timed out after forwarding the request.
+fn create_failed(e: Error) -> Step {
+    match e {
+        Error::Transport {
+            status: Some(status),
+            ..
+        } if status >= 500 => Step::Pending(Err(e)),
+        e => failed(e, Outcome::Error),
+    }
+}
+
+/// Undetermined results become `Pending(Err)`, so the deadline reports them as unknown
+fn undetermined(response: Response) -> Step {
+    Step::Pending(check_response(response))
+}
+
+/// Classify the response of a `pay` call
+fn after_pay(sent: Result<Response, Error>) -> Step {
+    let response = match sent {
+        Ok(response) => response,
+        Err(e) => return create_failed(e),
+    };
+    match response.result().result_status {
+        ResultStatus::S => Step::Done(Outcome::Succeeded(response)),
+        ResultStatus::F => Step::Done(Outcome::Failed(response)),
+        // the payment was created, the buyer has to complete it now
+        ResultStatus::U if response.is_processing() => Step::Done(Outcome::Processing(response)),
+        ResultStatus::U | ResultStatus::Other(_) => undetermined(response),
+    }
+}
+
+/// Classify the response of a payment inquiry
+fn after_payment_inquiry(sent: Result<Response, Error>) -> Step {
+    let response = match sent {
+        Ok(response) => response,
+        Err(e) => return failed(e, Outcome::Unknown),
+    };
+    let result = response.result();
+    match result.result_status {
+        ResultStatus::S => match response.get_payment_status() {
+            Some(PaymentStatus::SUCCESS) => Step::Done(Outcome::Succeeded(response)),
+            Some(PaymentStatus::FAIL) | Some(PaymentStatus::CANCELLED) => {
+                Step::Done(Outcome::Failed(response))
+            }
+            Some(PaymentStatus::PROCESSING)
+            | Some(PaymentStatus::PENDING)
+            | Some(PaymentStatus::Other(_))
+            | None => Step::Pending(Ok(response)),
+        },
+        // the payment request never reached Alipay
+        ResultStatus::F if result.result_code == ResultCode::ORDER_NOT_EXIST => {
+            Step::Done(Outcome::Failed(response))
+        }
+        ResultStatus::F => Step::Done(Outcome::Unknown(Error::from(result.clone()))),
+        ResultStatus::U | ResultStatus::Other(_) => undetermined(response),
+    }
+}
+
+/// Classify the response of a `refund` call
+fn after_refund(sent: Result<Response, Error>) -> Step {
+    let response = match sent {
+        Ok(response) => response,
+        Err(e) => return create_failed(e),
+    };
+    match response.result().result_status {
+        ResultStatus::S => Step::Done(Outcome::Succeeded(response)),
+        ResultStatus::F => Step::Done(Outcome::Failed(response)),
+        ResultStatus::U if response.result().result_code == ResultCode::REFUND_IN_PROCESS => {
+            Step::Pending(Ok(response))
+        }
+        ResultStatus::U | ResultStatus::Other(_) => undetermined(response),
+    }
+}
+
+/// Classify the response of a refund inquiry
+fn after_refund_inquiry(sent: Result<Response, Error>) -> Step {
+    let response = match sent {
+        Ok(response) => response,
+        Err(e) => return failed(e, Outcome::Unknown),
+    };
+    let result = response.result();
+    match result.result_status {
+        ResultStatus::S => match response.get_refund_status() {
+            Some(RefundStatus::SUCCESS) => Step::Done(Outcome::Succeeded(response)),
+            Some(RefundStatus::FAIL) => Step::Done(Outcome::Failed(response)),
+            Some(RefundStatus::PROCESSING) | Some(RefundStatus::Other(_)) | None => {
+                Step::Pending(Ok(response))
+            }
+        },
+        // the refund request never reached Alipay
+        ResultStatus::F if result.result_code == ResultCode::REFUND_NOT_EXIST => {
+            Step::Done(Outcome::Failed(response))
+        }
+        ResultStatus::F => Step::Done(Outcome::Unknown(Error::from(result.clone()))),
+        ResultStatus::U | ResultStatus::Other(_) => undetermined(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(value: serde_json::Value) -> Result<Response, Error> {
+        Ok(serde_json::from_value(value).unwrap())
+    }
+
+    #[test]
+    fn interval_grows_and_is_capped() {
+        let policy = ResolvePolicy {
+            initial_interval: Duration::from_millis(100),
+            max_interval: Duration::from_millis(300),
+            ..ResolvePolicy::default()
+        };
+        assert_eq!(policy.interval(1), Duration::from_millis(100));
+        assert_eq!(policy.interval(2), Duration::from_millis(200));
+        assert_eq!(policy.interval(3), Duration::from_millis(300));
+
+        let negative = ResolvePolicy {
+            multiplier: -2.0,
+            ..policy
+        };
+        assert_eq!(negative.interval(2), negative.max_interval);
+    }
+
+    #[test]
+    fn waits_never_pass_the_deadline() {
+        let policy = ResolvePolicy {
+            timeout: Duration::from_millis(50),
+            initial_interval: Duration::from_secs(1),
+            ..ResolvePolicy::default()
+        };
+        let mut polls = policy.start();
+        assert!(polls.next_wait().unwrap() <= Duration::from_millis(50));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(polls.next_wait().is_none());
+    }
+
+    #[test]
+    fn payment_in_process_is_handed_back_to_the_caller() {
+        let step = after_pay(response(serde_json::json!({
+            "result": {"resultCode": "PAYMENT_IN_PROCESS", "resultStatus": "U", "resultMessage": "in process"},
+            "normalUrl": "https://example.com/cashier"
+        })));
+        assert!(matches!(step, Step::Done(Outcome::Processing(_))));
+    }
+
+    #[test]
+    fn unknown_results_are_inquired() {
+        let step = after_pay(response(serde_json::json!({
+            "result": {"resultCode": "UNKNOWN_EXCEPTION", "resultStatus": "U", "resultMessage": "unknown"}
+        })));
+        let Step::Pending(last) = step else {
+            panic!("expected an inquiry")
+        };
+        assert!(matches!(Step::timed_out(last), Outcome::Unknown(_)));
+        assert!(matches!(
+            after_pay(Err(Error::transport("timed out"))),
+            Step::Pending(Err(_))
+        ));
+        assert!(matches!(
+            after_refund(Err(Error::status(502, "bad gateway"))),
+            Step::Pending(Err(_))
+        ));
+    }
+
+    #[test]
+    fn only_errors_that_leave_the_outcome_open_are_inquired() {
+        assert!(matches!(
+            after_pay(Err(Error::signing("agent down"))),
+            Step::Done(Outcome::Error(_))
+        ));
+        assert!(matches!(
+            after_refund(Err(Error::status(400, "bad request"))),
+            Step::Done(Outcome::Error(_))
+        ));
+        assert!(matches!(
+            after_pay(Err(Error::status(429, "too many requests"))),
+            Step::Done(Outcome::Error(_))
+        ));
+        // the payment may exist, but inquiring again will not tell
+        assert!(matches!(
+            after_payment_inquiry(Err(Error::config("missing key"))),
+            Step::Done(Outcome::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn missing_order_means_the_payment_failed() {
+        let step = after_payment_inquiry(response(serde_json::json!({
+            "result": {"resultCode": "ORDER_NOT_EXIST", "resultStatus": "F", "resultMessage": "not exist"}
+        })));
+        assert!(matches!(step, Step::Done(Outcome::Failed(_))));
+    }
+}
@@ -1,14 +1,13 @@
 use super::errors::Error;
 use super::models::Response;
 
-/// Prase Alipay Response
-pub(crate) fn parse_response(response_body: String) -> Result<Response, Error> {
-    let parsed: Response = serde_json::from_str::<Response>(&response_body).map_err(|e| {
-        Error::Unknown(format!(
-            "Failed to parse response body into base object: {}",
-            e.to_string()
-        ))
-    })?;
+/// Prase Alipay Response, a failed result is not turned into an error yet
+pub(crate) fn parse_response_body(response_body: &str) -> Result<Response, Error> {
+    Ok(serde_json::from_str::<Response>(response_body)?)
+}
+
+/// Turn a parsed response into an error when its result is not a success
+pub(crate) fn check_response(parsed: Response) -> Result<Response, Error> {
     let e = parsed.get_error();
     match e {
         Some(e) => Err(e),
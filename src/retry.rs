@@ -0,0 +1,198 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::errors::Error;
+use super::models::{Response, ResultCode};
+use super::sansio::AlipayProtocol;
+use super::transport::HttpResponse;
+
+/// When and how often a client re-sends a request.
+///
+/// Every attempt sends the same body, so the `payment_request_id` or
+/// `refund_request_id` stays the same and Alipay's idempotency control applies,
+/// but each attempt is signed again with a fresh `Request-Time`.
+///
+/// ```
+/// use std::time::Duration;
+/// use alipay_global::retry::RetryPolicy;
+///
+/// let policy = RetryPolicy {
+///     max_attempts: 5,
+///     initial_backoff: Duration::from_millis(500),
+///     ..RetryPolicy::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one. `1` disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two attempts
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after every attempt
+    pub multiplier: f64,
+    /// Fraction of the delay, between 0 and 1, that is randomly taken off
+    /// so that clients do not retry in lockstep
+    pub jitter: f64,
+    /// Result codes worth another attempt
+    pub retry_on: Vec<ResultCode>,
+    /// Also retry when no response came back, or on a 429 or 5xx status
+    pub retry_transport_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retry_on: vec![
+                ResultCode::UNKNOWN_EXCEPTION,
+                ResultCode::REQUEST_TRAFFIC_EXCEED_LIMIT,
+                ResultCode::SYSTEM_ERROR,
+            ],
+            retry_transport_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send every request exactly once
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// The delay before attempt `attempt + 1`, `attempt` starting at 1.
+    /// A negative or non finite delay, from a bad `multiplier` or `jitter`, is `max_backoff`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let capped = base.min(self.max_backoff.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 - jitter * rand::thread_rng().gen::<f64>();
+        Duration::try_from_secs_f64(capped * factor).unwrap_or(self.max_backoff)
+    }
+
+    pub fn retries_result_code(&self, result_code: &ResultCode) -> bool {
+        self.retry_on.contains(result_code)
+    }
+
+    fn retries_status(&self, status: u16) -> bool {
+        self.retry_transport_errors && (status == 429 || status >= 500)
+    }
+}
+
+/// The attempts at sending one request, without I/O.
+///
+/// From what came back it tells the client either the result or how long to back off
+/// before sending again. The clients only sign, send and sleep.
+pub(crate) struct Attempts<'a> {
+    policy: &'a RetryPolicy,
+    attempt: u32,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(policy: &'a RetryPolicy) -> Self {
+        Self { policy, attempt: 1 }
+    }
+
+    /// The result to return, or the delay before the next attempt
+    pub(crate) fn next(
+        &mut self,
+        settled: Settled,
+    ) -> ControlFlow<Result<Response, Error>, Duration> {
+        if !settled.retryable || self.attempt >= self.policy.max_attempts {
+            return ControlFlow::Break(settled.result);
+        }
+        let wait = self.policy.backoff(self.attempt);
+        self.attempt += 1;
+        ControlFlow::Continue(wait)
+    }
+}
+
+/// The result of one attempt, and whether it is worth another one.
+/// A failed result is still an `Ok` response here, see `check_response`.
+pub(crate) struct Settled {
+    pub(crate) result: Result<Response, Error>,
+    pub(crate) retryable: bool,
+}
+
+/// Verify what came back from one attempt and decide whether to retry it
+pub(crate) fn settle(
+    policy: &RetryPolicy,
+    protocol: &AlipayProtocol,
+    path: &str,
+    sent: Result<HttpResponse, Error>,
+) -> Settled {
+    let response = match sent {
+        Ok(response) => response,
+        Err(e) => {
+            return Settled {
+                result: Err(e),
+                retryable: policy.retry_transport_errors,
+            }
+        }
+    };
+    let status = response.status;
+    match protocol.verify_response_body(path, response) {
+        Ok(parsed) => Settled {
+            retryable: policy.retries_result_code(&parsed.result().result_code),
+            result: Ok(parsed),
+        },
+        Err(e) => Settled {
+            result: Err(e),
+            retryable: policy.retries_status(status),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+        assert_eq!(policy.backoff(30), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.backoff(1);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn bad_factors_fall_back_to_the_maximum() {
+        let policy = RetryPolicy {
+            multiplier: -2.0,
+            jitter: f64::NAN,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), policy.max_backoff);
+        assert_eq!(policy.backoff(2), policy.max_backoff);
+    }
+}
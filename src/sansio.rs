@@ -0,0 +1,841 @@
+//! Sans-IO request preparation and response verification.
+//!
+//! Use [`AlipayProtocol`] to call Alipay through your own HTTP stack: prepare a
+//! signed request, send it however you like, then hand the status, headers and
+//! body back to verify the signature and parse the result.
+//!
+//! ```no_run
+//! use alipay_global::models::{AlipayClientSecret, CashierPaymentInquiry};
+//! use alipay_global::sansio::AlipayProtocol;
+//! use alipay_global::Endpoint;
+//!
+//! # fn send(url: &str, headers: &[(String, String)], body: &[u8]) -> (u16, Vec<(String, String)>, String) { unimplemented!() }
+//! # fn run(secret: AlipayClientSecret) {
+//! let protocol = AlipayProtocol::new(&secret).unwrap();
+//! let inquiry = CashierPaymentInquiry {
+//!     payment_request_id: Some(String::from("order-1")),
+//!     payment_id: None,
+//! };
+//! let prepared = protocol.prepare_request(Endpoint::InquiryPayment, &inquiry).unwrap();
+//! let (status, headers, body) = send(&prepared.url, &prepared.headers, prepared.body_bytes());
+//! let r = protocol.process_response(Endpoint::InquiryPayment, status, headers, &body);
+//! # }
+//! ```
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+use super::endpoint::{Endpoint, Environment};
+use super::errors::{Error, Key, KeyError, WebhookError};
+use super::keys::AlipayPublicKeys;
+use super::models::{
+    AlipayClientSecret, HasPrivateKey, HasPublicKey, RequestEnv, Response, ResponseResult,
+    ResultCode, ResultStatus, Signable, WebhookData, WebhookResponse, WebhookResponseInput,
+    WebhookResponseResult,
+};
+use super::notification::Notification;
+use super::reload::{self, KeyFileSigner, PublicKeys};
+use super::replay::{replay_key, WebhookPolicy};
+use super::response::{check_response, parse_response_body};
+use super::sign::{sign, verify};
+use super::signature::SignatureHeader;
+use super::signer::{RsaSigner, Signer, DEFAULT_KEY_VERSION};
+use super::transport::{HttpRequest, HttpResponse};
+
+/// Parsed keys and identity, everything needed to talk to Alipay except the I/O.
+/// Both [`AlipayClient`](crate::AlipayClient) and the async client are built on it.
+/// A clone shares the signer, the keys and the replay store.
+#[derive(Clone)]
+pub struct AlipayProtocol {
+    client_id: String,
+    environment: Environment,
+    live_payments_allowed: bool,
+    base_url: String,
+    signer: Arc<dyn Signer>,
+    alipay_public_keys: PublicKeys,
+    webhook_policy: WebhookPolicy,
+}
+
+/// A signed request, ready to be sent by any HTTP client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedRequest {
+    pub method: String,
+    pub url: String,
+    /// The path the signature covers, responses are verified against it too
+    pub path: String,
+    /// `Content-Type`, `Signature`, `client-id` and `Request-Time`
+    pub headers: Vec<(String, String)>,
+    /// The exact body that was signed, it must be sent unchanged
+    pub body: String,
+}
+
+impl PreparedRequest {
+    pub fn body_bytes(&self) -> &[u8] {
+        self.body.as_bytes()
+    }
+}
+
+impl From<PreparedRequest> for HttpRequest {
+    fn from(value: PreparedRequest) -> Self {
+        Self {
+            method: value.method,
+            url: value.url,
+            headers: value.headers,
+            body: value.body,
+        }
+    }
+}
+
+/// The public key of the merchant key a protocol signs with now
+type MerchantPublicKey = Box<dyn Fn() -> RsaPublicKey + Send + Sync>;
+
+impl AlipayProtocol {
+    /// Load and parse both keys of a secret. Refuses keys that cannot sign or verify, and a
+    /// secret whose client id, pinned keys and environment disagree. Problems that leave the
+    /// keys usable are only reported by [`validate`](AlipayClientSecret::validate).
+    pub fn new(secret: &AlipayClientSecret) -> Result<Self, Error> {
+        let private_key = secret.get_private_key()?;
+        private_key.validate().map_err(|e| KeyError::Invalid {
+            key: Key::MerchantPrivate,
+            reason: e.to_string(),
+        })?;
+        let alipay_public_key = secret.get_public_key()?;
+        Self::with_keys(secret, private_key, alipay_public_key)
+    }
+
+    /// Use keys that are already parsed, e.g. with [`keys::parse_private_key`](crate::keys::parse_private_key)
+    /// from a byte slice. The key fields of `secret` are ignored, except `key_version`.
+    /// Refuses keys pinned to the other environment, and a client id of the other environment.
+    pub fn with_keys(
+        secret: &AlipayClientSecret,
+        private_key: RsaPrivateKey,
+        alipay_public_keys: impl Into<AlipayPublicKeys>,
+    ) -> Result<Self, Error> {
+        secret
+            .key_pins()
+            .check(Key::MerchantPrivate, &RsaPublicKey::from(&private_key))?;
+        let key_version = secret.key_version.as_deref().unwrap_or(DEFAULT_KEY_VERSION);
+        Self::with_signer(
+            secret,
+            RsaSigner::with_key_version(private_key, key_version),
+            alipay_public_keys,
+        )
+    }
+
+    /// Sign through `signer`, e.g. when the private key is held by a KMS or is being
+    /// rotated with a [`RotatingSigner`](crate::signer::RotatingSigner).
+    /// The private key fields of `secret` are ignored. Refuses Alipay public keys pinned
+    /// to the other environment, and a client id of the other environment.
+    pub fn with_signer(
+        secret: &AlipayClientSecret,
+        signer: impl Signer + 'static,
+        alipay_public_keys: impl Into<AlipayPublicKeys>,
+    ) -> Result<Self, Error> {
+        secret.check_environment(None, None)?;
+        let alipay_public_keys = alipay_public_keys.into();
+        let pins = secret.key_pins();
+        for alipay_public_key in alipay_public_keys.keys() {
+            pins.check(Key::AlipayPublic, alipay_public_key)?;
+        }
+        Ok(Self::from_parts(
+            secret,
+            Arc::new(signer),
+            PublicKeys::from(alipay_public_keys),
+        ))
+    }
+
+    /// Like [`new`](Self::new), but `private_key_pem_file` and `alipay_public_key_pem_file`
+    /// are read again whenever they change. A changed key that fails to load, that is pinned
+    /// to the other environment, or that [`validate`](AlipayClientSecret::validate) would
+    /// report, is passed to `on_error` and the previous key stays in use.
+    pub fn with_key_reload(
+        secret: &AlipayClientSecret,
+        on_error: impl Fn(&KeyError) + Send + Sync + 'static,
+    ) -> Result<Self, Error> {
+        // the client id is checked once here, each key whenever it is loaded
+        secret.check_environment(None, None)?;
+        let pins = secret.key_pins();
+        let on_error = reload::hook(on_error);
+        let key_version = secret.key_version.as_deref().unwrap_or(DEFAULT_KEY_VERSION);
+        // a reloaded alipay key is compared with the merchant key in use at that time
+        let (signer, merchant_public_key): (Arc<dyn Signer>, MerchantPublicKey) =
+            match &secret.private_key_pem_file {
+                Some(path) => {
+                    let mut signer = KeyFileSigner::with_hook(
+                        path.to_path_buf(),
+                        secret.private_key_passphrase()?,
+                        Some(pins.clone()),
+                        on_error.clone(),
+                    )?;
+                    signer.set_key_version(key_version);
+                    let signer = Arc::new(signer);
+                    let current = signer.clone();
+                    (signer, Box::new(move || current.public_key()))
+                }
+                None => {
+                    let private_key = secret.get_private_key()?;
+                    let public_key = RsaPublicKey::from(&private_key);
+                    pins.check(Key::MerchantPrivate, &public_key)?;
+                    (
+                        Arc::new(RsaSigner::with_key_version(private_key, key_version)),
+                        Box::new(move || public_key.clone()),
+                    )
+                }
+            };
+        let alipay_public_keys = match &secret.alipay_public_key_pem_file {
+            Some(path) => {
+                PublicKeys::file(path.to_path_buf(), pins, merchant_public_key, on_error)?
+            }
+            None => {
+                let alipay_public_key = secret.get_public_key()?;
+                pins.check(Key::AlipayPublic, &alipay_public_key)?;
+                PublicKeys::from(AlipayPublicKeys::new(alipay_public_key))
+            }
+        };
+        Ok(Self::from_parts(secret, signer, alipay_public_keys))
+    }
+
+    fn from_parts(
+        secret: &AlipayClientSecret,
+        signer: Arc<dyn Signer>,
+        alipay_public_keys: PublicKeys,
+    ) -> Self {
+        Self {
+            client_id: secret.client_id.clone(),
+            environment: secret.environment(),
+            live_payments_allowed: secret.live_payments_allowed,
+            base_url: secret
+                .base_url
+                .clone()
+                .unwrap_or_else(|| String::from(secret.region.domain())),
+            signer,
+            alipay_public_keys,
+            webhook_policy: WebhookPolicy::default(),
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// How notifications are checked for freshness and replays.
+    /// Neither is checked by default, see [`WebhookPolicy::fresh`].
+    pub fn set_webhook_policy(&mut self, webhook_policy: WebhookPolicy) {
+        self.webhook_policy = webhook_policy;
+    }
+
+    pub fn is_sandbox(&self) -> bool {
+        self.environment.is_sandbox()
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.environment
+    }
+
+    /// Where requests are sent, the region gateway or the configured override
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn request_env(&self, endpoint: Endpoint) -> RequestEnv {
+        RequestEnv::with_base_url(&self.base_url, self.environment.is_sandbox(), endpoint)
+    }
+
+    /// Sign `body` for `endpoint` with the current time as `Request-Time`
+    pub fn prepare_request(
+        &self,
+        endpoint: Endpoint,
+        body: &impl Signable,
+    ) -> Result<PreparedRequest, Error> {
+        self.prepare_request_at(endpoint, body, Utc::now())
+    }
+
+    /// Sign `body` for `endpoint` with the given `Request-Time`
+    pub fn prepare_request_at(
+        &self,
+        endpoint: Endpoint,
+        body: &impl Signable,
+        utc: DateTime<Utc>,
+    ) -> Result<PreparedRequest, Error> {
+        self.check_environment()?;
+        let request_env = self.request_env(endpoint);
+        let signed = sign(
+            "POST",
+            &request_env.path,
+            &self.client_id,
+            utc,
+            self.signer.as_ref(),
+            body,
+        )?;
+        Ok(PreparedRequest {
+            method: String::from("POST"),
+            url: request_env.get_request_url(),
+            headers: vec![
+                (
+                    String::from("Content-Type"),
+                    String::from("application/json"),
+                ),
+                (String::from("Signature"), signed.to_string()),
+                (String::from("client-id"), self.client_id.clone()),
+                (String::from("Request-Time"), format_time(utc)),
+            ],
+            path: request_env.path,
+            body: body.get_value().to_string(),
+        })
+    }
+
+    /// No request leaves with a client id of the other environment,
+    /// nor for the live API unless `live_payments_allowed` is set
+    fn check_environment(&self) -> Result<(), Error> {
+        if Environment::of_client_id(&self.client_id) != self.environment {
+            return Err(Error::config(format!(
+                "client id {} does not belong to the {} environment",
+                self.client_id, self.environment
+            )));
+        }
+        if self.environment == Environment::Live && !self.live_payments_allowed {
+            return Err(Error::config(
+                "live payments are not allowed, set live_payments_allowed to call the live API",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Verify the signature of a response to a request sent to `endpoint`, then parse it.
+    /// Header names are matched case-insensitively.
+    pub fn process_response<K, V>(
+        &self,
+        endpoint: Endpoint,
+        status: u16,
+        headers: impl IntoIterator<Item = (K, V)>,
+        body: &str,
+    ) -> Result<Response, Error>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let response = HttpResponse {
+            status,
+            headers: headers
+                .into_iter()
+                .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+                .collect(),
+            body: body.to_string(),
+        };
+        self.verify_response(&self.request_env(endpoint).path, response)
+    }
+
+    /// Verify the signature of a response to a request sent to `path`, then parse it
+    pub(crate) fn verify_response(
+        &self,
+        path: &str,
+        response: HttpResponse,
+    ) -> Result<Response, Error> {
+        check_response(self.verify_response_body(path, response)?)
+    }
+
+    /// Like `verify_response`, but a failed result is returned as a response, not an error
+    pub(crate) fn verify_response_body(
+        &self,
+        path: &str,
+        response: HttpResponse,
+    ) -> Result<Response, Error> {
+        if !response.is_success() {
+            return Err(Error::status(response.status, response.body));
+        }
+        let header_signature = required_header(&response, "Signature")?;
+        let response_time = required_header(&response, "Response-Time")?;
+        let client_id = required_header(&response, "Client-Id")?;
+        verify(
+            path,
+            "POST",
+            response_time,
+            header_signature,
+            client_id,
+            &response.body,
+            &self.alipay_public_keys.current(),
+        )?;
+        parse_response_body(&response.body)
+    }
+
+    /// Verify a notification sent by Alipay and parse its body
+    pub fn verify_webhook(&self, webhook_data: &WebhookData) -> Result<Response, Error> {
+        self.verify_webhook_at(webhook_data, Utc::now())
+    }
+
+    /// Verify a notification as if it was received at `now`.
+    /// A stale or replayed notification is refused with [`Error::Webhook`].
+    pub fn verify_webhook_at(
+        &self,
+        webhook_data: &WebhookData,
+        now: DateTime<Utc>,
+    ) -> Result<Response, Error> {
+        self.authenticate(webhook_data, now)?;
+        let response = parse_response_body(&webhook_data.request_body)?;
+        self.record(webhook_data, now)?;
+        Ok(response)
+    }
+
+    /// Verify a notification sent by Alipay and parse it according to its type
+    pub fn verify_notification(&self, webhook_data: &WebhookData) -> Result<Notification, Error> {
+        self.verify_notification_at(webhook_data, Utc::now())
+    }
+
+    /// Verify a notification as if it was received at `now`, see [`Self::verify_webhook_at`]
+    pub fn verify_notification_at(
+        &self,
+        webhook_data: &WebhookData,
+        now: DateTime<Utc>,
+    ) -> Result<Notification, Error> {
+        self.authenticate(webhook_data, now)?;
+        let notification = Notification::parse(&webhook_data.request_body)?;
+        self.record(webhook_data, now)?;
+        Ok(notification)
+    }
+
+    /// Check the signature and, with a clock skew set, the time of a notification
+    fn authenticate(&self, webhook_data: &WebhookData, now: DateTime<Utc>) -> Result<(), Error> {
+        verify(
+            &webhook_data.path,
+            &webhook_data.method,
+            &webhook_data.request_time,
+            &webhook_data.header_signature,
+            &webhook_data.client_id,
+            &webhook_data.request_body,
+            &self.alipay_public_keys.current(),
+        )?;
+        if let Some(max_clock_skew) = self.webhook_policy.max_clock_skew {
+            check_fresh(&webhook_data.request_time, now, max_clock_skew)?;
+        }
+        Ok(())
+    }
+
+    /// Record a notification in the replay store, refusing it if it was received before.
+    /// It is kept until the freshness check refuses its deliveries anyway.
+    fn record(&self, webhook_data: &WebhookData, now: DateTime<Utc>) -> Result<(), Error> {
+        if let Some(store) = &self.webhook_policy.replay_store {
+            let key = webhook_replay_key(webhook_data)?;
+            let keep = match self.webhook_policy.max_clock_skew {
+                Some(max_clock_skew) => {
+                    let sent = parse_time(&webhook_data.request_time)?;
+                    Some(match (now - sent).to_std() {
+                        Ok(age) => max_clock_skew.saturating_sub(age),
+                        // sent ahead of our clock
+                        Err(_) => max_clock_skew + (sent - now).to_std().unwrap_or_default(),
+                    })
+                }
+                None => None,
+            };
+            if !store.record(&key, keep).map_err(WebhookError::Store)? {
+                return Err(WebhookError::Replayed(key).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget a notification, so its next delivery is accepted, e.g. when handling it failed
+    pub fn forget_webhook(&self, webhook_data: &WebhookData) -> Result<(), Error> {
+        if let Some(store) = &self.webhook_policy.replay_store {
+            let key = webhook_replay_key(webhook_data)?;
+            store.forget(&key).map_err(WebhookError::Store)?;
+        }
+        Ok(())
+    }
+
+    /// Build the signed acknowledgement telling Alipay the notification was handled
+    pub fn webhook_success_response(
+        &self,
+        webhook_response_in: WebhookResponseInput,
+    ) -> Result<WebhookResponse, Error> {
+        success_acknowledgement(self.signer.as_ref(), webhook_response_in)
+    }
+
+    /// Build the signed acknowledgement asking Alipay to deliver the notification again
+    pub fn webhook_failed_response(
+        &self,
+        webhook_response_in: WebhookResponseInput,
+    ) -> Result<WebhookResponse, Error> {
+        failed_acknowledgement(self.signer.as_ref(), webhook_response_in)
+    }
+}
+
+/// The acknowledgement of a handled notification, signed by `signer`
+pub(crate) fn success_acknowledgement(
+    signer: &dyn Signer,
+    webhook_response_in: WebhookResponseInput,
+) -> Result<WebhookResponse, Error> {
+    acknowledgement(
+        signer,
+        webhook_response_in,
+        ResponseResult {
+            result_code: ResultCode::SUCCESS,
+            result_status: ResultStatus::S,
+            result_message: String::from("Success"),
+        },
+    )
+}
+
+/// The acknowledgement asking for a notification again, signed by `signer`
+pub(crate) fn failed_acknowledgement(
+    signer: &dyn Signer,
+    webhook_response_in: WebhookResponseInput,
+) -> Result<WebhookResponse, Error> {
+    acknowledgement(
+        signer,
+        webhook_response_in,
+        ResponseResult {
+            result_code: ResultCode::PARAM_ILLEGAL,
+            result_status: ResultStatus::F,
+            result_message: String::from("The required parameters are not passed, or illegal parameters exist. For example, a non-numeric input, an invalid date, or the length and type of the parameter are wrong."),
+        },
+    )
+}
+
+fn acknowledgement(
+    signer: &dyn Signer,
+    webhook_response_in: WebhookResponseInput,
+    result: ResponseResult,
+) -> Result<WebhookResponse, Error> {
+    let utc_now = Utc::now();
+    let response_result_content = WebhookResponseResult { result };
+    let signed = sign(
+        &webhook_response_in.method,
+        &webhook_response_in.path,
+        &webhook_response_in.client_id,
+        utc_now,
+        signer,
+        &response_result_content,
+    )?;
+    Ok(WebhookResponse {
+        full_signature: signed.to_string(),
+        client_id: webhook_response_in.client_id,
+        response_time: format_time(utc_now),
+        body: response_result_content.get_value().to_string(),
+    })
+}
+
+fn check_fresh(
+    request_time: &str,
+    now: DateTime<Utc>,
+    max_clock_skew: Duration,
+) -> Result<(), WebhookError> {
+    let sent = parse_time(request_time)?;
+    let skew = (now - sent).abs();
+    match skew.to_std() {
+        Ok(skew) if skew <= max_clock_skew => Ok(()),
+        _ => Err(WebhookError::Stale {
+            request_time: request_time.to_string(),
+            max_clock_skew,
+        }),
+    }
+}
+
+fn parse_time(request_time: &str) -> Result<DateTime<Utc>, WebhookError> {
+    DateTime::parse_from_rfc3339(request_time)
+        .map(|sent| sent.with_timezone(&Utc))
+        .map_err(|_| WebhookError::InvalidTime(request_time.to_string()))
+}
+
+/// Notifications without an id are told apart by their signature
+fn webhook_replay_key(webhook_data: &WebhookData) -> Result<String, Error> {
+    match replay_key(&webhook_data.request_body) {
+        Some(key) => Ok(key),
+        None => {
+            let header = SignatureHeader::parse(&webhook_data.header_signature)?;
+            Ok(format!("signature:{}", header.signature()))
+        }
+    }
+}
+
+fn format_time(utc: DateTime<Utc>) -> String {
+    utc.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
+
+fn required_header<'a>(response: &'a HttpResponse, name: &str) -> Result<&'a str, Error> {
+    response
+        .header(name)
+        .ok_or_else(|| Error::signature(format!("response is missing the {} header", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+    use rsa::pkcs1::{EncodeRsaPublicKey, LineEnding};
+    use serde_json::json;
+
+    use super::*;
+    use crate::keys::fingerprint;
+    use crate::models::{
+        load_private_key, load_public_key, CashierPaymentInquiry, HasPrivateKey, HasPublicKey,
+        PinnedKey,
+    };
+    use crate::replay::MemoryReplayStore;
+    use crate::test_support::{alipay_notification, fixture, merchant_secret, FakeAlipay};
+    use crate::transport::Transport;
+
+    fn inquiry() -> CashierPaymentInquiry {
+        CashierPaymentInquiry {
+            payment_request_id: Some(String::from("order-1")),
+            payment_id: None,
+        }
+    }
+
+    fn path() -> String {
+        String::from("/ams/sandbox/api/v1/payments/inquiryPayment")
+    }
+
+    #[test]
+    fn prepares_signed_request_without_io() {
+        let protocol = AlipayProtocol::new(&merchant_secret()).unwrap();
+        let utc = Utc.with_ymd_and_hms(2023, 7, 1, 8, 0, 0).unwrap();
+        let prepared = protocol
+            .prepare_request_at(Endpoint::InquiryPayment, &inquiry(), utc)
+            .unwrap();
+
+        assert_eq!(prepared.path, "/ams/sandbox/api/v1/payments/inquiryPayment");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(prepared.body_bytes()).unwrap(),
+            json!({"paymentRequestId": "order-1", "paymentId": null})
+        );
+        let request = HttpRequest::from(prepared.clone());
+        assert_eq!(
+            request.header("request-time"),
+            Some("2023-07-01T08:00:00+00:00")
+        );
+        assert_eq!(request.header("client-id"), Some("SANDBOX_TEST"));
+
+        // the merchant public key, as uploaded to Alipay, accepts the signature
+        let merchant_public_key = load_public_key(&fixture("merchant_public_key.pem")).unwrap();
+        verify(
+            &prepared.path,
+            "POST",
+            "2023-07-01T08:00:00+00:00",
+            request.header("signature").unwrap(),
+            "SANDBOX_TEST",
+            &prepared.body,
+            &merchant_public_key.into(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn builds_with_usable_keys_that_validate_reports() {
+        let mut secret = merchant_secret();
+        let pkcs1 = secret
+            .get_public_key()
+            .unwrap()
+            .to_pkcs1_pem(LineEnding::LF)
+            .unwrap();
+        secret.alipay_public_key_pem_file = None;
+        secret.alipay_public_key_pem = Some(pkcs1);
+        assert!(AlipayProtocol::new(&secret).is_ok());
+        assert!(!secret.validate().is_ok());
+    }
+
+    #[test]
+    fn signs_through_a_custom_signer() {
+        struct Kms(RsaSigner);
+        impl Signer for Kms {
+            fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+                self.0.sign_digest(digest)
+            }
+            fn key_version(&self) -> &str {
+                "2"
+            }
+        }
+        let secret = merchant_secret();
+        let kms = Kms(RsaSigner::new(secret.get_private_key().unwrap()));
+        let protocol =
+            AlipayProtocol::with_signer(&secret, kms, secret.get_public_key().unwrap()).unwrap();
+        let utc = Utc.with_ymd_and_hms(2023, 7, 1, 8, 0, 0).unwrap();
+        let prepared = protocol
+            .prepare_request_at(Endpoint::InquiryPayment, &inquiry(), utc)
+            .unwrap();
+        let request = HttpRequest::from(prepared.clone());
+        let signature = request.header("signature").unwrap();
+        assert!(signature.starts_with("algorithm=RSA256,keyVersion=2,signature="));
+
+        let merchant_public_key = load_public_key(&fixture("merchant_public_key.pem")).unwrap();
+        verify(
+            &prepared.path,
+            "POST",
+            "2023-07-01T08:00:00+00:00",
+            signature,
+            "SANDBOX_TEST",
+            &prepared.body,
+            &merchant_public_key.into(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn keys_given_directly_are_checked_against_the_environment() {
+        let mut secret = merchant_secret();
+        let private_key = secret.get_private_key().unwrap();
+        let alipay_public_key = secret.get_public_key().unwrap();
+        secret.pinned_keys.push(PinnedKey {
+            environment: Environment::Live,
+            fingerprint: fingerprint(&RsaPublicKey::from(&private_key)).unwrap(),
+        });
+        assert!(matches!(
+            AlipayProtocol::with_keys(&secret, private_key.clone(), alipay_public_key.clone()),
+            Err(Error::Key(KeyError::Invalid {
+                key: Key::MerchantPrivate,
+                ..
+            }))
+        ));
+
+        secret.pinned_keys.clear();
+        secret.sandbox = false;
+        let signer = RsaSigner::new(private_key);
+        assert!(matches!(
+            AlipayProtocol::with_signer(&secret, signer, alipay_public_key),
+            Err(Error::Config { .. })
+        ));
+    }
+
+    #[test]
+    fn verifies_with_the_key_of_the_header_version() {
+        let secret = merchant_secret();
+        let mut keys =
+            AlipayPublicKeys::new(load_public_key(&fixture("merchant_public_key.pem")).unwrap());
+        keys.insert("2", secret.get_public_key().unwrap());
+        let protocol =
+            AlipayProtocol::with_keys(&secret, secret.get_private_key().unwrap(), keys).unwrap();
+        let answer = json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+        });
+
+        let mut fake = FakeAlipay::new(vec![answer.clone(), answer]);
+        fake.signer = RsaSigner::with_key_version(
+            load_private_key(&fixture("alipay_private_key.pem")).unwrap(),
+            "2",
+        );
+        let prepared = protocol
+            .prepare_request(Endpoint::InquiryPayment, &inquiry())
+            .unwrap();
+        let response = fake.send(prepared.into()).unwrap();
+        protocol.verify_response(&path(), response).unwrap();
+
+        // signed with the new key but announced as the old version
+        fake.signer = RsaSigner::new(load_private_key(&fixture("alipay_private_key.pem")).unwrap());
+        let prepared = protocol
+            .prepare_request(Endpoint::InquiryPayment, &inquiry())
+            .unwrap();
+        let response = fake.send(prepared.into()).unwrap();
+        assert!(matches!(
+            protocol.verify_response(&path(), response),
+            Err(Error::Signature { .. })
+        ));
+    }
+
+    #[test]
+    fn guards_the_environment() {
+        let mut secret = merchant_secret();
+        secret.sandbox = false;
+        assert!(matches!(
+            AlipayProtocol::new(&secret),
+            Err(Error::Config { .. })
+        ));
+
+        // a live account, as staging would configure it
+        secret.client_id = String::from("LIVE_TEST");
+        let protocol = AlipayProtocol::new(&secret).unwrap();
+        assert!(matches!(
+            protocol.prepare_request(Endpoint::InquiryPayment, &inquiry()),
+            Err(Error::Config { .. })
+        ));
+
+        secret.live_payments_allowed = true;
+        let protocol = AlipayProtocol::new(&secret).unwrap();
+        let prepared = protocol
+            .prepare_request(Endpoint::InquiryPayment, &inquiry())
+            .unwrap();
+        assert_eq!(prepared.path, "/ams/api/v1/payments/inquiryPayment");
+    }
+
+    #[test]
+    fn refuses_stale_and_replayed_notifications() {
+        let mut protocol = AlipayProtocol::new(&merchant_secret()).unwrap();
+        protocol.set_webhook_policy(WebhookPolicy {
+            replay_store: Some(Arc::new(MemoryReplayStore::default())),
+            ..WebhookPolicy::fresh()
+        });
+        let sent = Utc.with_ymd_and_hms(2023, 7, 1, 8, 0, 0).unwrap();
+        let notification = alipay_notification(
+            "/notify",
+            json!({
+                "notifyType": "PAYMENT_RESULT",
+                "paymentId": "p1",
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+            }),
+            sent,
+        );
+
+        let late = sent + chrono::Duration::minutes(6);
+        assert!(matches!(
+            protocol.verify_webhook_at(&notification, late),
+            Err(Error::Webhook(WebhookError::Stale { .. }))
+        ));
+
+        let now = sent + chrono::Duration::seconds(20);
+        protocol.verify_webhook_at(&notification, now).unwrap();
+        assert!(matches!(
+            protocol.verify_webhook_at(&notification, now),
+            Err(Error::Webhook(WebhookError::Replayed(key))) if key == "PAYMENT_RESULT:p1"
+        ));
+
+        // handling failed, the next delivery is accepted
+        protocol.forget_webhook(&notification).unwrap();
+        protocol.verify_webhook_at(&notification, now).unwrap();
+    }
+
+    #[test]
+    fn processes_signed_response() {
+        let protocol = AlipayProtocol::new(&merchant_secret()).unwrap();
+        let prepared = protocol
+            .prepare_request(Endpoint::InquiryPayment, &inquiry())
+            .unwrap();
+        let fake = FakeAlipay::new(vec![json!({
+            "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"}
+        })]);
+        let response = fake.send(prepared.into()).unwrap();
+
+        let parsed = protocol
+            .process_response(
+                Endpoint::InquiryPayment,
+                response.status,
+                response.headers.clone(),
+                &response.body,
+            )
+            .unwrap();
+        assert!(parsed.is_success());
+
+        // signed for another path
+        assert!(protocol
+            .process_response(
+                Endpoint::Refund,
+                response.status,
+                response.headers,
+                &response.body
+            )
+            .is_err());
+        // missing headers
+        assert!(protocol
+            .process_response(
+                Endpoint::InquiryPayment,
+                200,
+                Vec::<(String, String)>::new(),
+                &response.body
+            )
+            .is_err());
+        // non 2xx status
+        assert!(protocol
+            .process_response(Endpoint::InquiryPayment, 500, [("a", "b")], "oops")
+            .is_err());
+    }
+}
@@ -1,49 +1,73 @@
-use std::{
-    borrow::BorrowMut,
-    io::{Error, Result, ErrorKind},
-};
-use base64::Engine;
-use rsa::{
-    pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePublicKey, Hash, PaddingScheme, PublicKey,
-    RsaPrivateKey, RsaPublicKey,
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
 };
+use rsa::{Hash, PaddingScheme, PublicKey, RsaPublicKey};
+
+use super::errors::{Error, SignatureStage};
+use super::keys::AlipayPublicKeys;
 
 use sha2::{Digest, Sha256};
 
-use crate::models::{HasPrivateKey, HasPublicKey};
+use super::models::Signable;
+use super::signature::SignatureHeader;
+use super::signer::Signer;
 
-use super::models::{AlipayClientSecret, RequestEnv, Signable};
+/// Alipay pads the signatures it sends, while ours are encoded without padding,
+/// so decoding accepts both forms.
+const SIGNATURE_ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
 
-/// Perform a rsa sign for request
-fn rsa_sign(content: &str, private_key: &impl HasPrivateKey, hash: Option<Hash>) -> String {
-    let digest = Sha256::digest(content.as_bytes());
+/// Perform a rsa sign for request, the header names the key that signed
+fn rsa_sign(content: &str, signer: &dyn Signer) -> Result<SignatureHeader, Error> {
+    let digest: [u8; 32] = Sha256::digest(content.as_bytes()).into();
 
-    let pk = private_key.get_private_key().unwrap();
+    let (key_version, signature_byte) = signer.sign_versioned(&digest).map_err(signing_failed)?;
+    Ok(SignatureHeader::new(
+        key_version,
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature_byte),
+    ))
+}
 
-    let signature_byte = pk.sign(
-        PaddingScheme::new_pkcs1v15_sign(hash),
-        digest.as_slice(),
-    ).unwrap();
-    base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature_byte)
+/// Nothing reached Alipay when a signer fails, whatever error it returns
+fn signing_failed(e: Error) -> Error {
+    match e {
+        Error::Signature { reason, source, .. } => Error::Signature {
+            stage: SignatureStage::Sign,
+            reason,
+            source,
+        },
+        e @ (Error::Key(_) | Error::Config { .. }) => e,
+        e => Error::signing_caused_by("the signer failed", e),
+    }
 }
 
 // Perform a rsa verfiy for response
-fn rsa_verify(content: &str, signature: &str, public_key: &impl HasPublicKey, hash: Option<Hash>) -> Result<()> {
-    let pbk = public_key.get_public_key().unwrap();
-
+fn rsa_verify(
+    content: &str,
+    signature: &str,
+    public_key: &RsaPublicKey,
+    hash: Option<Hash>,
+) -> Result<(), Error> {
     let mut hashed = Sha256::new();
     hashed.update(content.as_bytes());
-    if let Ok(decode_signature) = base64::decode(signature) {
-        match pbk.verify(
+    if let Ok(decode_signature) = SIGNATURE_ENGINE.decode(signature) {
+        match public_key.verify(
             PaddingScheme::new_pkcs1v15_sign(hash),
             &hashed.finalize(),
             &decode_signature,
         ) {
             Ok(()) => Ok(()),
-            Err(err) => Err(Error::new(ErrorKind::Other, err.to_string())),
+            Err(err) => Err(Error::signature_caused_by(
+                "the signature does not match",
+                err.to_string(),
+            )),
         }
     } else {
-        Err(Error::new(ErrorKind::Other, "base64 decode signature failed"))
+        Err(Error::signature("the signature is not valid base64"))
     }
 }
 
@@ -61,32 +85,23 @@ fn get_alipay_raw_request(
         path,
         client_id,
         iso_utc,
-        signable.get_value().to_string()
+        signable.get_value()
     )
 }
+
 /// Sign a request
 pub(crate) fn sign(
     method: &str,
-    sign_path: Option<String>,
-    sign_client_id: Option<String>,
+    path: &str,
+    client_id: &str,
     utc: chrono::DateTime<chrono::Utc>,
-    secret: &AlipayClientSecret,
-    signable: &impl Signable
-) -> String {
-    let RequestEnv { path, .. } = RequestEnv::from(secret);
-    let AlipayClientSecret { client_id, .. } = secret;
-
-    // let utc = chrono::Utc::now();
+    signer: &dyn Signer,
+    signable: &impl Signable,
+) -> Result<SignatureHeader, Error> {
     let iso_utc = utc.to_rfc3339_opts(chrono::SecondsFormat::Secs, false);
 
-    let content = get_alipay_raw_request(
-        method,
-        sign_path.unwrap_or(path).as_str(),
-        sign_client_id.unwrap_or(client_id.to_owned()).as_str(),
-        &iso_utc,
-        signable
-    );
-    rsa_sign(&content, secret, Some(Hash::SHA2_256))
+    let content = get_alipay_raw_request(method, path, client_id, &iso_utc, signable);
+    rsa_sign(&content, signer)
 }
 
 fn get_alipay_raw_response(
@@ -98,34 +113,27 @@ fn get_alipay_raw_response(
 ) -> String {
     format!(
         "{} {}\n{}.{}.{}",
-        method,
-        path,
-        client_id,
-        iso_utc,
-        verify_content,
+        method, path, client_id, iso_utc, verify_content,
     )
 }
 
+/// Verify a response or a notification sent by Alipay with the key of its `keyVersion`
 pub(crate) fn verify(
-    verify_path: Option<String>,
+    path: &str,
     method: &str,
     response_time: &str,
     header_signature: &str,
     client_id: &str,
     response_body: &str,
-    secret: &AlipayClientSecret
-) -> Result<()> {
-    let RequestEnv { path, .. } = RequestEnv::from(secret);
-
-    let content = get_alipay_raw_response(
-        method,
-        verify_path.unwrap_or(path).as_str(),
-        client_id,
-        response_time,
-        response_body
-    );
-    println!("content: {}", content);
-    let signature: Vec<&str> = header_signature.split("signature=").collect();
-    let urldecoded_sig = urlencoding::decode(signature[1]).unwrap().into_owned();
-    rsa_verify(&content, &urldecoded_sig, secret, Some(Hash::SHA2_256))
+    public_keys: &AlipayPublicKeys,
+) -> Result<(), Error> {
+    let header = SignatureHeader::parse(header_signature)?;
+    let public_key = public_keys.get(header.key_version())?;
+    let content = get_alipay_raw_response(method, path, client_id, response_time, response_body);
+    rsa_verify(
+        &content,
+        header.signature(),
+        public_key,
+        Some(Hash::SHA2_256),
+    )
 }
@@ -0,0 +1,196 @@
+//! The `Signature` header of requests, responses and notifications.
+//!
+//! ```
+//! use alipay_global::signature::SignatureHeader;
+//!
+//! let header: SignatureHeader = "algorithm=RSA256,keyVersion=2,signature=ab%2Bc%3D".parse().unwrap();
+//! assert_eq!(header.key_version(), Some("2"));
+//! assert_eq!(header.signature(), "ab+c=");
+//! assert_eq!(header.to_string(), "algorithm=RSA256,keyVersion=2,signature=ab%2Bc%3D");
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::errors::Error;
+
+/// The only algorithm Alipay Global signs with
+pub const ALGORITHM: &str = "RSA256";
+
+/// `algorithm=RSA256,keyVersion=<version>,signature=<url encoded base64>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureHeader {
+    key_version: Option<String>,
+    signature: String,
+}
+
+/// Why a `Signature` header cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// A field is not `name=value`
+    Malformed(String),
+    /// A field other than `algorithm`, `keyVersion` and `signature`
+    UnknownField(String),
+    /// A field is given twice
+    DuplicateField(String),
+    /// `algorithm` or `signature` is missing or empty
+    MissingField(&'static str),
+    /// The algorithm is not `RSA256`
+    UnsupportedAlgorithm(String),
+    /// The signature is not valid URL encoding
+    InvalidEncoding,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Malformed(field) => write!(f, "malformed field {:?}", field),
+            HeaderError::UnknownField(name) => write!(f, "unknown field {}", name),
+            HeaderError::DuplicateField(name) => write!(f, "duplicate field {}", name),
+            HeaderError::MissingField(name) => write!(f, "missing field {}", name),
+            HeaderError::UnsupportedAlgorithm(algorithm) => {
+                write!(f, "unsupported algorithm {}", algorithm)
+            }
+            HeaderError::InvalidEncoding => write!(f, "the signature is not url encoded"),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+impl From<HeaderError> for Error {
+    fn from(value: HeaderError) -> Self {
+        Error::signature_caused_by("invalid signature header", value)
+    }
+}
+
+impl SignatureHeader {
+    /// A header for a base64 `signature` made with the key of `key_version`
+    pub fn new(key_version: impl Into<String>, signature: impl Into<String>) -> Self {
+        Self {
+            key_version: Some(key_version.into()),
+            signature: signature.into(),
+        }
+    }
+
+    /// Parse a header, rejecting anything that is not exactly the three known fields
+    pub fn parse(header: &str) -> Result<Self, HeaderError> {
+        let mut algorithm = None;
+        let mut key_version = None;
+        let mut signature = None;
+        for field in header.split(',') {
+            let (name, value) = field
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| HeaderError::Malformed(field.to_string()))?;
+            let slot = match name {
+                "algorithm" => &mut algorithm,
+                "keyVersion" => &mut key_version,
+                "signature" => &mut signature,
+                _ => return Err(HeaderError::UnknownField(name.to_string())),
+            };
+            if slot.replace(value).is_some() {
+                return Err(HeaderError::DuplicateField(name.to_string()));
+            }
+        }
+        match algorithm {
+            None | Some("") => return Err(HeaderError::MissingField("algorithm")),
+            Some(ALGORITHM) => {}
+            Some(other) => return Err(HeaderError::UnsupportedAlgorithm(other.to_string())),
+        }
+        let signature = match signature {
+            None | Some("") => return Err(HeaderError::MissingField("signature")),
+            Some(signature) => urlencoding::decode(signature)
+                .map_err(|_| HeaderError::InvalidEncoding)?
+                .into_owned(),
+        };
+        Ok(Self {
+            key_version: key_version.filter(|v| !v.is_empty()).map(String::from),
+            signature,
+        })
+    }
+
+    /// The version of the key the signature was made with, if the header tells
+    pub fn key_version(&self) -> Option<&str> {
+        self.key_version.as_deref()
+    }
+
+    /// The base64 signature, URL decoded
+    pub fn signature(&self) -> &str {
+        &self.signature
+    }
+}
+
+impl FromStr for SignatureHeader {
+    type Err = HeaderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for SignatureHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "algorithm={}", ALGORITHM)?;
+        if let Some(key_version) = &self.key_version {
+            write!(f, ",keyVersion={}", key_version)?;
+        }
+        write!(f, ",signature={}", urlencoding::encode(&self.signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let header = SignatureHeader::new("1", "a/b+c==");
+        assert_eq!(
+            header.to_string(),
+            "algorithm=RSA256,keyVersion=1,signature=a%2Fb%2Bc%3D%3D"
+        );
+        assert_eq!(SignatureHeader::parse(&header.to_string()).unwrap(), header);
+
+        let without_version = SignatureHeader::parse("algorithm=RSA256,signature=abc").unwrap();
+        assert_eq!(without_version.key_version(), None);
+        assert_eq!(
+            without_version.to_string(),
+            "algorithm=RSA256,signature=abc"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let parse = |s: &str| SignatureHeader::parse(s).unwrap_err();
+        assert_eq!(
+            parse("signature=abc"),
+            HeaderError::MissingField("algorithm")
+        );
+        assert_eq!(
+            parse("algorithm=RSA256,keyVersion=1"),
+            HeaderError::MissingField("signature")
+        );
+        assert_eq!(
+            parse("algorithm=SHA1,signature=abc"),
+            HeaderError::UnsupportedAlgorithm(String::from("SHA1"))
+        );
+        assert_eq!(
+            parse("algorithm=RSA256,charset=utf8,signature=abc"),
+            HeaderError::UnknownField(String::from("charset"))
+        );
+        assert_eq!(
+            parse("algorithm=RSA256,signature=abc,signature=def"),
+            HeaderError::DuplicateField(String::from("signature"))
+        );
+        assert_eq!(
+            parse("algorithm=RSA256,abc"),
+            HeaderError::Malformed(String::from("abc"))
+        );
+        assert_eq!(
+            parse("algorithm=RSA256,signature=%FF"),
+            HeaderError::InvalidEncoding
+        );
+        assert_eq!(parse(""), HeaderError::Malformed(String::new()));
+    }
+}
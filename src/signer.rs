@@ -0,0 +1,522 @@
+//! Where signatures come from.
+//!
+//! Requests and webhook acknowledgements are signed through a [`Signer`], so the
+//! merchant private key does not have to live in this process. [`RsaSigner`] holds
+//! the key in memory; [`CommandSigner`] and [`UnixSocketSigner`] delegate to a
+//! local signing process, e.g. an agent in front of a KMS or an HSM.
+//! [`RotatingSigner`] holds several versioned keys while a key is rotated.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use rsa::{Hash, PaddingScheme, RsaPrivateKey};
+
+use super::errors::Error;
+
+/// The key version sent in the `Signature` header when none is configured
+pub const DEFAULT_KEY_VERSION: &str = "1";
+
+/// How long [`CommandSigner`] and [`UnixSocketSigner`] wait for a signature when none is configured
+pub const DEFAULT_SIGNER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a running signing command is checked for exit
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Signs with the merchant private key
+pub trait Signer: Send + Sync {
+    /// Sign a SHA-256 digest with RSASSA-PKCS1-v1_5 and return the raw signature bytes.
+    /// Fail with [`Error::signing`], any other error is reported as a signing error too.
+    fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error>;
+
+    /// The version of the key, sent as `keyVersion` in the `Signature` header
+    fn key_version(&self) -> &str {
+        DEFAULT_KEY_VERSION
+    }
+
+    /// Sign a digest and return the version of the key that signed it with the signature.
+    /// Signers that switch keys override this, so both always come from the same key.
+    fn sign_versioned(&self, digest: &[u8; 32]) -> Result<(String, Vec<u8>), Error> {
+        let signature = self.sign_digest(digest)?;
+        Ok((self.key_version().to_string(), signature))
+    }
+}
+
+impl<T: Signer + ?Sized> Signer for std::sync::Arc<T> {
+    fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        self.as_ref().sign_digest(digest)
+    }
+
+    fn key_version(&self) -> &str {
+        self.as_ref().key_version()
+    }
+
+    fn sign_versioned(&self, digest: &[u8; 32]) -> Result<(String, Vec<u8>), Error> {
+        self.as_ref().sign_versioned(digest)
+    }
+}
+
+/// Signs with a private key held in memory
+pub struct RsaSigner {
+    private_key: RsaPrivateKey,
+    key_version: String,
+}
+
+impl RsaSigner {
+    pub fn new(private_key: RsaPrivateKey) -> Self {
+        Self::with_key_version(private_key, DEFAULT_KEY_VERSION)
+    }
+
+    pub fn with_key_version(private_key: RsaPrivateKey, key_version: impl Into<String>) -> Self {
+        Self {
+            private_key,
+            key_version: key_version.into(),
+        }
+    }
+}
+
+impl Signer for RsaSigner {
+    fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        sign_with_key(&self.private_key, digest)
+    }
+
+    fn key_version(&self) -> &str {
+        &self.key_version
+    }
+}
+
+/// Several merchant keys tagged with their version, one of which signs.
+///
+/// While a new key is rolled out in the Alipay portal, both keys are valid: add
+/// the new one, then [`activate`](Self::activate) it once Alipay accepts it.
+/// Keys are added before the signer is installed, activating only needs `&self`,
+/// so a signer shared as an `Arc` with a running client can still be switched.
+pub struct RotatingSigner {
+    signers: Vec<Box<dyn Signer>>,
+    active: AtomicUsize,
+}
+
+impl RotatingSigner {
+    /// Start with `active` as the only and active key
+    pub fn new(active: impl Signer + 'static) -> Self {
+        Self {
+            signers: vec![Box::new(active)],
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a key, replacing the one with the same version. The active key does not change.
+    pub fn add(&mut self, signer: impl Signer + 'static) {
+        let signer: Box<dyn Signer> = Box::new(signer);
+        match self.position(signer.key_version()) {
+            Some(i) => self.signers[i] = signer,
+            None => self.signers.push(signer),
+        }
+    }
+
+    /// Sign with the key of `key_version` from now on
+    pub fn activate(&self, key_version: &str) -> Result<(), Error> {
+        let position = self
+            .position(key_version)
+            .ok_or_else(|| Error::config(format!("no merchant key has version {}", key_version)))?;
+        self.active.store(position, Ordering::Release);
+        Ok(())
+    }
+
+    /// The versions of all keys, in the order they were added
+    pub fn key_versions(&self) -> impl Iterator<Item = &str> {
+        self.signers.iter().map(|s| s.key_version())
+    }
+
+    fn active(&self) -> &dyn Signer {
+        self.signers[self.active.load(Ordering::Acquire)].as_ref()
+    }
+
+    fn position(&self, key_version: &str) -> Option<usize> {
+        self.signers
+            .iter()
+            .position(|s| s.key_version() == key_version)
+    }
+}
+
+impl Signer for RotatingSigner {
+    fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        self.active().sign_digest(digest)
+    }
+
+    fn key_version(&self) -> &str {
+        self.active().key_version()
+    }
+
+    fn sign_versioned(&self, digest: &[u8; 32]) -> Result<(String, Vec<u8>), Error> {
+        // one read of the active key, an activation in between cannot split them
+        self.active().sign_versioned(digest)
+    }
+}
+
+/// Runs a command for every signature.
+///
+/// The command gets the base64 digest on stdin and must print the base64
+/// signature on stdout, then exit with status 0. A command still running after the
+/// timeout is killed and the signature fails.
+pub struct CommandSigner {
+    program: PathBuf,
+    args: Vec<String>,
+    key_version: String,
+    timeout: Duration,
+}
+
+impl CommandSigner {
+    pub fn new(program: impl Into<PathBuf>, args: Vec<String>) -> Self {
+        Self {
+            program: program.into(),
+            args,
+            key_version: String::from(DEFAULT_KEY_VERSION),
+            timeout: DEFAULT_SIGNER_TIMEOUT,
+        }
+    }
+
+    pub fn set_key_version(&mut self, key_version: impl Into<String>) {
+        self.key_version = key_version.into();
+    }
+
+    /// Kill the command if it has not exited after `timeout`, see [`DEFAULT_SIGNER_TIMEOUT`]
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+impl Signer for CommandSigner {
+    fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        let failed = |e: std::io::Error| {
+            Error::signing_caused_by(format!("cannot run {}", self.program.display()), e)
+        };
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(failed)?;
+        if let Some(mut stdin) = child.stdin.take() {
+            writeln!(stdin, "{}", encode(digest)).map_err(failed)?;
+        }
+        // the pipes are drained aside, so a chatty command cannot block on a full pipe
+        let stdout = read_aside(child.stdout.take());
+        let stderr = read_aside(child.stderr.take());
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(failed)? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::signing(format!(
+                    "{} did not answer within {:?}",
+                    self.program.display(),
+                    self.timeout
+                )));
+            }
+            std::thread::sleep(COMMAND_POLL_INTERVAL);
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            return Err(Error::signing(format!(
+                "{} exited with {}: {}",
+                self.program.display(),
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            )));
+        }
+        decode(&String::from_utf8_lossy(&stdout))
+    }
+
+    fn key_version(&self) -> &str {
+        &self.key_version
+    }
+}
+
+/// Asks a signing agent listening on a Unix socket.
+///
+/// Every signature opens a connection, writes the base64 digest and a newline,
+/// and reads the base64 signature back up to a newline. An agent that does not
+/// answer within the timeout fails the signature instead of blocking the request.
+#[cfg(unix)]
+pub struct UnixSocketSigner {
+    path: PathBuf,
+    key_version: String,
+    timeout: Duration,
+}
+
+#[cfg(unix)]
+impl UnixSocketSigner {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            key_version: String::from(DEFAULT_KEY_VERSION),
+            timeout: DEFAULT_SIGNER_TIMEOUT,
+        }
+    }
+
+    pub fn set_key_version(&mut self, key_version: impl Into<String>) {
+        self.key_version = key_version.into();
+    }
+
+    /// Wait at most `timeout` on each read and write, see [`DEFAULT_SIGNER_TIMEOUT`]
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+#[cfg(unix)]
+impl Signer for UnixSocketSigner {
+    fn sign_digest(&self, digest: &[u8; 32]) -> Result<Vec<u8>, Error> {
+        let failed = |e: std::io::Error| {
+            Error::signing_caused_by(
+                format!("cannot reach the signer at {}", self.path.display()),
+                e,
+            )
+        };
+        let mut stream = std::os::unix::net::UnixStream::connect(&self.path).map_err(failed)?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(failed)?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(failed)?;
+        writeln!(stream, "{}", encode(digest)).map_err(failed)?;
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .map_err(failed)?;
+        decode(&line)
+    }
+
+    fn key_version(&self) -> &str {
+        &self.key_version
+    }
+}
+
+pub(crate) fn sign_with_key(
+    private_key: &RsaPrivateKey,
+    digest: &[u8; 32],
+) -> Result<Vec<u8>, Error> {
+    private_key
+        .sign(
+            PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+            digest,
+        )
+        .map_err(|e| Error::signing_caused_by("failed to sign the digest", e.to_string()))
+}
+
+fn read_aside(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+fn decode(line: &str) -> Result<Vec<u8>, Error> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Err(Error::signing("the signer returned no signature"));
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(line)
+        .map_err(|e| Error::signing_caused_by("the signer returned invalid base64", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::PublicKey;
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    use crate::models::{load_private_key, load_public_key};
+    use crate::test_support::fixture;
+
+    fn rsa_signer() -> RsaSigner {
+        RsaSigner::new(load_private_key(&fixture("merchant_private_key.pem")).unwrap())
+    }
+
+    #[test]
+    fn signs_with_the_active_version() {
+        let old = rsa_signer();
+        let new = RsaSigner::with_key_version(
+            load_private_key(&fixture("alipay_private_key.pem")).unwrap(),
+            "2",
+        );
+        let digest: [u8; 32] = Sha256::digest(b"content").into();
+        let expected = new.sign_digest(&digest).unwrap();
+
+        let mut signer = RotatingSigner::new(old);
+        signer.add(new);
+        assert_eq!(signer.key_version(), "1");
+        assert!(signer.activate("3").is_err());
+        signer.activate("2").unwrap();
+        assert_eq!(signer.key_version(), "2");
+        assert_eq!(signer.sign_digest(&digest).unwrap(), expected);
+        assert_eq!(signer.key_versions().collect::<Vec<_>>(), ["1", "2"]);
+    }
+
+    #[test]
+    fn names_the_key_that_signed_while_keys_are_switched() {
+        let mut signer = RotatingSigner::new(rsa_signer());
+        signer.add(RsaSigner::with_key_version(
+            load_private_key(&fixture("alipay_private_key.pem")).unwrap(),
+            "2",
+        ));
+        let signer = std::sync::Arc::new(signer);
+        let public_keys = [
+            (
+                "1",
+                load_public_key(&fixture("merchant_public_key.pem")).unwrap(),
+            ),
+            (
+                "2",
+                load_public_key(&fixture("alipay_public_key.pem")).unwrap(),
+            ),
+        ];
+        let digest: [u8; 32] = Sha256::digest(b"content").into();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let signer = signer.clone();
+                std::thread::spawn(move || {
+                    (0..10)
+                        .map(|_| signer.sign_versioned(&digest).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        // switch keys for as long as the workers sign
+        for version in ["1", "2"].iter().cycle() {
+            if workers.iter().all(|worker| worker.is_finished()) {
+                break;
+            }
+            signer.activate(version).unwrap();
+        }
+        for worker in workers {
+            for (key_version, signature) in worker.join().unwrap() {
+                let (_, public_key) = public_keys
+                    .iter()
+                    .find(|(version, _)| *version == key_version)
+                    .unwrap();
+                public_key
+                    .verify(
+                        PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+                        &digest,
+                        &signature,
+                    )
+                    .unwrap();
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delegates_to_a_command() {
+        let signer = CommandSigner::new(
+            "sh",
+            vec![String::from("-c"), String::from("read d; echo \"$d\"")],
+        );
+        let digest: [u8; 32] = Sha256::digest(b"content").into();
+        assert_eq!(signer.sign_digest(&digest).unwrap(), digest.to_vec());
+
+        let failing = CommandSigner::new("sh", vec![String::from("-c"), String::from("exit 3")]);
+        assert!(matches!(
+            failing.sign_digest(&digest),
+            Err(Error::Signature { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kills_a_command_that_does_not_answer() {
+        let mut hanging = CommandSigner::new(
+            "sh",
+            vec![String::from("-c"), String::from("exec sleep 30")],
+        );
+        hanging.set_timeout(Duration::from_millis(100));
+        let digest: [u8; 32] = Sha256::digest(b"content").into();
+        let started = Instant::now();
+        assert!(matches!(
+            hanging.sign_digest(&digest),
+            Err(Error::Signature {
+                stage: crate::errors::SignatureStage::Sign,
+                ..
+            })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delegates_to_a_unix_socket_agent() {
+        let dir = std::env::temp_dir().join(format!("alipay-signer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        // a stand-in agent holding the key in another thread
+        let agent = std::thread::spawn(move || {
+            let key = rsa_signer();
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            let digest: [u8; 32] = decode(&line).unwrap().try_into().unwrap();
+            let signature = key.sign_digest(&digest).unwrap();
+            writeln!(&stream, "{}", encode(&signature)).unwrap();
+        });
+
+        let digest: [u8; 32] = Sha256::digest(b"content").into();
+        let signer = UnixSocketSigner::new(&path);
+        let signature = signer.sign_digest(&digest).unwrap();
+        agent.join().unwrap();
+        assert_eq!(signature, rsa_signer().sign_digest(&digest).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn gives_up_on_an_agent_that_does_not_answer() {
+        let dir = std::env::temp_dir().join(format!("alipay-silent-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        // accepts the connection but never writes the signature back
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let agent = std::thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            let _ = released.recv();
+        });
+
+        let digest: [u8; 32] = Sha256::digest(b"content").into();
+        let mut signer = UnixSocketSigner::new(&path);
+        signer.set_timeout(Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        let result = signer.sign_digest(&digest);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(result, Err(Error::Signature { .. })));
+        release.send(()).unwrap();
+        agent.join().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
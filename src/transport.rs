@@ -0,0 +1,162 @@
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+
+use super::errors::Error;
+
+/// A signed request, ready to be sent by any HTTP client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// What came back from Alipay, before any verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpRequest {
+    /// Look up a header, ignoring the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+impl HttpResponse {
+    /// Look up a header, ignoring the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Sends prepared requests to Alipay.
+///
+/// Implement this to drive the client with your own instrumented HTTP client,
+/// or with an in-memory fake in tests. A non 2xx status must be returned as an
+/// `HttpResponse`, errors are for requests that got no response at all.
+pub trait Transport: Send + Sync {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error>;
+}
+
+impl<T: Transport + ?Sized> Transport for std::sync::Arc<T> {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        self.as_ref().send(request)
+    }
+}
+
+/// The default [`Transport`], backed by a pooled `ureq::Agent`
+pub struct UreqTransport {
+    agent: ureq::Agent,
+}
+
+impl UreqTransport {
+    pub fn new(agent: ureq::Agent) -> Self {
+        Self { agent }
+    }
+}
+
+impl Transport for UreqTransport {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let mut req = self.agent.request(&request.method, &request.url);
+        for (name, value) in &request.headers {
+            req = req.set(name, value);
+        }
+        let resp = match req.send_string(&request.body) {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(_, resp)) => resp,
+            Err(e) => return Err(Error::from(e)),
+        };
+        let status = resp.status();
+        let headers = resp
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = resp.header(&name)?.to_string();
+                Some((name, value))
+            })
+            .collect();
+        let body = resp.into_string()?;
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// The future an [`AsyncTransport`] answers with
+#[cfg(feature = "async")]
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<HttpResponse, Error>> + Send + 'a>>;
+
+/// Sends prepared requests to Alipay without blocking, the async counterpart of [`Transport`]
+#[cfg(feature = "async")]
+pub trait AsyncTransport: Send + Sync {
+    fn send(&self, request: HttpRequest) -> SendFuture<'_>;
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncTransport + ?Sized> AsyncTransport for std::sync::Arc<T> {
+    fn send(&self, request: HttpRequest) -> SendFuture<'_> {
+        self.as_ref().send(request)
+    }
+}
+
+/// The default [`AsyncTransport`], backed by a `reqwest::Client`
+#[cfg(feature = "async")]
+pub struct ReqwestTransport {
+    http: reqwest::Client,
+}
+
+#[cfg(feature = "async")]
+impl ReqwestTransport {
+    pub fn new(http: reqwest::Client) -> Self {
+        Self { http }
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTransport for ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> SendFuture<'_> {
+        Box::pin(async move {
+            let method = reqwest::Method::from_bytes(request.method.as_bytes())
+                .map_err(|e| Error::config_caused_by("invalid request method", e))?;
+            let mut req = self.http.request(method, request.url);
+            for (name, value) in request.headers {
+                req = req.header(name, value);
+            }
+            let resp = req.body(request.body).send().await?;
+            let status = resp.status().as_u16();
+            let headers = resp
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect();
+            let body = resp.text().await?;
+            Ok(HttpResponse {
+                status,
+                headers,
+                body,
+            })
+        })
+    }
+}
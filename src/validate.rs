@@ -0,0 +1,413 @@
+//! Check a client configuration before the first payment depends on it.
+//!
+//! ```no_run
+//! # fn run(secret: alipay_global::models::AlipayClientSecret) -> Result<(), alipay_global::errors::Error> {
+//! let report = secret.validate();
+//! for problem in report.problems() {
+//!     eprintln!("{}", problem);
+//! }
+//! report.into_result()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+
+use rsa::{PublicKeyParts, RsaPrivateKey, RsaPublicKey};
+
+use super::endpoint::{Environment, SANDBOX_CLIENT_ID_PREFIX};
+use super::errors::{Error, Key, KeyError};
+use super::keygen::{KeyPair, KEY_BITS};
+use super::keys::{fingerprint, is_pkcs1_public_key};
+use super::models::{AlipayClientSecret, HasPrivateKey, HasPublicKey, PinnedKey};
+
+/// Something that stops a configuration from working
+#[derive(Debug)]
+pub enum Problem {
+    /// The merchant private key cannot be loaded or is not a consistent RSA key
+    MerchantKey(KeyError),
+    /// The merchant private key is shorter than Alipay accepts
+    MerchantKeyTooShort { bits: usize },
+    /// A signature made with the merchant private key does not verify with its own public key
+    SelfCheckFailed(KeyError),
+    /// The Alipay public key cannot be loaded
+    AlipayKey(KeyError),
+    /// The Alipay public key is the merchant's own public key
+    AlipayKeyIsMerchantKey,
+    /// The Alipay public key is PKCS#1, the Alipay portal expects SPKI
+    AlipayKeyIsPkcs1,
+    /// A sandbox client id with a live setup, or the other way round
+    ClientIdMismatch {
+        client_id: String,
+        environment: Environment,
+    },
+    /// A key pinned to the other environment
+    KeyPinnedElsewhere {
+        key: Key,
+        fingerprint: String,
+        pinned_to: Environment,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MerchantKey(e) | Problem::AlipayKey(e) => write!(f, "{}", e),
+            Problem::MerchantKeyTooShort { bits } => write!(
+                f,
+                "the merchant private key has {} bits, at least {} are required",
+                bits, KEY_BITS
+            ),
+            Problem::SelfCheckFailed(e) => write!(f, "the sign and verify round trip failed: {}", e),
+            Problem::AlipayKeyIsMerchantKey => write!(
+                f,
+                "the alipay public key is the merchant public key, use the key shown by the Alipay portal"
+            ),
+            Problem::AlipayKeyIsPkcs1 => write!(
+                f,
+                "the alipay public key is a PKCS#1 RSA PUBLIC KEY, use the SPKI PUBLIC KEY shown by the Alipay portal"
+            ),
+            Problem::ClientIdMismatch {
+                client_id,
+                environment: Environment::Sandbox,
+            } => write!(
+                f,
+                "sandbox is set but client id {} does not start with {}",
+                client_id, SANDBOX_CLIENT_ID_PREFIX
+            ),
+            Problem::ClientIdMismatch {
+                client_id,
+                environment: Environment::Live,
+            } => write!(
+                f,
+                "sandbox is not set but client id {} is a sandbox id",
+                client_id
+            ),
+            Problem::KeyPinnedElsewhere {
+                key,
+                fingerprint,
+                pinned_to,
+            } => write!(
+                f,
+                "the {} {} is pinned to {}, not {}",
+                key,
+                fingerprint,
+                pinned_to,
+                pinned_to.other()
+            ),
+        }
+    }
+}
+
+/// Every problem found in a configuration, empty when it is usable
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    /// A configuration error listing every problem, if there is any
+    pub fn into_result(self) -> Result<(), Error> {
+        if self.is_ok() {
+            return Ok(());
+        }
+        let reasons: Vec<String> = self.problems.iter().map(|p| p.to_string()).collect();
+        Err(Error::config(reasons.join("; ")))
+    }
+}
+
+impl AlipayClientSecret {
+    /// Load both keys and check them and the client id against each other, without any request
+    pub fn validate(&self) -> ValidationReport {
+        let mut problems = Vec::new();
+        let merchant = match self.get_private_key() {
+            Ok(private_key) => Some(private_key),
+            Err(e) => {
+                problems.push(Problem::MerchantKey(e));
+                None
+            }
+        };
+        let alipay_public_key = match self.get_public_key() {
+            Ok(alipay_public_key) => Some(alipay_public_key),
+            Err(e) => {
+                problems.push(Problem::AlipayKey(e));
+                None
+            }
+        };
+        problems.extend(self.key_problems(merchant, alipay_public_key.as_ref()));
+        ValidationReport { problems }
+    }
+
+    fn key_problems(
+        &self,
+        private_key: Option<RsaPrivateKey>,
+        alipay_public_key: Option<&RsaPublicKey>,
+    ) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let merchant = private_key.map(KeyPair::from_private_key);
+        if let Some(pair) = &merchant {
+            problems.extend(merchant_key_problems(pair));
+        }
+
+        if let Some(alipay_public_key) = alipay_public_key {
+            if merchant
+                .as_ref()
+                .is_some_and(|pair| pair.public_key() == alipay_public_key)
+            {
+                problems.push(Problem::AlipayKeyIsMerchantKey);
+            }
+            if self
+                .alipay_public_key_bytes()
+                .is_ok_and(|input| is_pkcs1_public_key(&input))
+            {
+                problems.push(Problem::AlipayKeyIsPkcs1);
+            }
+        }
+
+        problems.extend(self.environment_problems(
+            merchant.as_ref().map(KeyPair::public_key),
+            alipay_public_key,
+        ));
+        problems
+    }
+
+    /// Refuse a configuration whose client id, pinned keys and environment disagree.
+    /// A key that is not given is not checked.
+    pub(crate) fn check_environment(
+        &self,
+        merchant_public_key: Option<&RsaPublicKey>,
+        alipay_public_key: Option<&RsaPublicKey>,
+    ) -> Result<(), Error> {
+        ValidationReport {
+            problems: self.environment_problems(merchant_public_key, alipay_public_key),
+        }
+        .into_result()
+    }
+
+    /// What keys loaded later, e.g. by a reload, are checked against
+    pub(crate) fn key_pins(&self) -> KeyPins {
+        KeyPins {
+            environment: self.environment(),
+            pinned_keys: self.pinned_keys.clone(),
+        }
+    }
+
+    fn environment_problems(
+        &self,
+        merchant_public_key: Option<&RsaPublicKey>,
+        alipay_public_key: Option<&RsaPublicKey>,
+    ) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let environment = self.environment();
+        if Environment::of_client_id(&self.client_id) != environment {
+            problems.push(Problem::ClientIdMismatch {
+                client_id: self.client_id.clone(),
+                environment,
+            });
+        }
+        let pins = self.key_pins();
+        let keys = [
+            (Key::MerchantPrivate, merchant_public_key),
+            (Key::AlipayPublic, alipay_public_key),
+        ];
+        for (key, public_key) in keys {
+            match public_key.map(|public_key| pins.problem(key, public_key)) {
+                Some(Ok(problem)) => problems.extend(problem),
+                Some(Err(e)) if key == Key::MerchantPrivate => {
+                    problems.push(Problem::MerchantKey(e))
+                }
+                Some(Err(e)) => problems.push(Problem::AlipayKey(e)),
+                None => {}
+            }
+        }
+        problems
+    }
+}
+
+/// What `validate` checks on the merchant key alone
+fn merchant_key_problems(pair: &KeyPair) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if let Err(e) = pair.private_key().validate() {
+        problems.push(Problem::MerchantKey(KeyError::Invalid {
+            key: Key::MerchantPrivate,
+            reason: e.to_string(),
+        }));
+    }
+    let bits = pair.public_key().size() * 8;
+    if bits < KEY_BITS {
+        problems.push(Problem::MerchantKeyTooShort { bits });
+    }
+    if let Err(e) = pair.self_check() {
+        problems.push(Problem::SelfCheckFailed(e));
+    }
+    problems
+}
+
+/// Refuse a merchant key, e.g. a reloaded one, that `validate` would report
+pub(crate) fn check_merchant_key(private_key: &RsaPrivateKey) -> Result<(), KeyError> {
+    let pair = KeyPair::from_private_key(private_key.clone());
+    refuse(Key::MerchantPrivate, merchant_key_problems(&pair))
+}
+
+/// Refuse an Alipay public key, e.g. a reloaded one, that is the merchant's own
+pub(crate) fn check_alipay_key(
+    alipay_public_key: &RsaPublicKey,
+    merchant_public_key: &RsaPublicKey,
+) -> Result<(), KeyError> {
+    let mut problems = Vec::new();
+    if alipay_public_key == merchant_public_key {
+        problems.push(Problem::AlipayKeyIsMerchantKey);
+    }
+    refuse(Key::AlipayPublic, problems)
+}
+
+/// The first problem as the error of `key`
+fn refuse(key: Key, problems: Vec<Problem>) -> Result<(), KeyError> {
+    match problems.into_iter().next() {
+        None => Ok(()),
+        Some(Problem::MerchantKey(e) | Problem::AlipayKey(e) | Problem::SelfCheckFailed(e)) => {
+            Err(e)
+        }
+        Some(problem) => Err(KeyError::Invalid {
+            key,
+            reason: problem.to_string(),
+        }),
+    }
+}
+
+/// The environment of a configuration and the keys pinned to an environment
+#[derive(Debug, Clone)]
+pub(crate) struct KeyPins {
+    environment: Environment,
+    pinned_keys: Vec<PinnedKey>,
+}
+
+impl KeyPins {
+    /// Refuse a key pinned to the other environment
+    pub(crate) fn check(&self, key: Key, public_key: &RsaPublicKey) -> Result<(), KeyError> {
+        match self.problem(key, public_key)? {
+            Some(problem) => Err(KeyError::Invalid {
+                key,
+                reason: problem.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn problem(&self, key: Key, public_key: &RsaPublicKey) -> Result<Option<Problem>, KeyError> {
+        let fingerprint = fingerprint(public_key).map_err(|e| KeyError::Invalid {
+            key,
+            reason: e.to_string(),
+        })?;
+        let pinned_elsewhere = self.pinned_keys.iter().any(|pin| {
+            pin.environment != self.environment
+                && pin.fingerprint.eq_ignore_ascii_case(&fingerprint)
+        });
+        Ok(pinned_elsewhere.then(|| Problem::KeyPinnedElsewhere {
+            key,
+            fingerprint,
+            pinned_to: self.environment.other(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs1::{EncodeRsaPublicKey, LineEnding};
+
+    use crate::test_support::{fixture_path, merchant_secret};
+
+    #[test]
+    fn a_working_configuration_has_no_problems() {
+        let report = merchant_secret().validate();
+        assert!(report.is_ok(), "{:?}", report.problems());
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn lists_every_problem() {
+        let mut secret = merchant_secret();
+        secret.sandbox = false;
+        secret.private_key_pem_file = None;
+        secret.alipay_public_key_pem_file =
+            Some(Box::new(fixture_path("merchant_private_key.pem")));
+
+        let report = secret.validate();
+        let problems = report.problems();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(matches!(
+            problems[0],
+            Problem::MerchantKey(KeyError::Missing(_))
+        ));
+        assert!(matches!(
+            problems[1],
+            Problem::AlipayKey(KeyError::Invalid { .. })
+        ));
+        assert!(matches!(
+            problems[2],
+            Problem::ClientIdMismatch {
+                environment: Environment::Live,
+                ..
+            }
+        ));
+        assert!(matches!(report.into_result(), Err(Error::Config { .. })));
+    }
+
+    #[test]
+    fn refuses_keys_pinned_to_the_other_environment() {
+        let mut secret = merchant_secret();
+        let alipay_public_key = secret.get_public_key().unwrap();
+        secret.pinned_keys.push(PinnedKey {
+            environment: Environment::Live,
+            fingerprint: fingerprint(&alipay_public_key).unwrap().to_uppercase(),
+        });
+        let report = secret.validate();
+        assert!(matches!(
+            report.problems(),
+            [Problem::KeyPinnedElsewhere {
+                key: Key::AlipayPublic,
+                pinned_to: Environment::Live,
+                ..
+            }]
+        ));
+
+        secret.pinned_keys[0].environment = Environment::Sandbox;
+        assert!(secret.validate().is_ok());
+    }
+
+    #[test]
+    fn reports_a_pkcs1_alipay_key() {
+        let mut secret = merchant_secret();
+        let pkcs1 = secret
+            .get_public_key()
+            .unwrap()
+            .to_pkcs1_pem(LineEnding::LF)
+            .unwrap();
+        secret.alipay_public_key_pem_file = None;
+        secret.alipay_public_key_pem = Some(pkcs1);
+        assert!(matches!(
+            secret.validate().problems(),
+            [Problem::AlipayKeyIsPkcs1]
+        ));
+    }
+
+    #[test]
+    fn catches_the_merchant_key_used_as_alipay_key() {
+        let mut secret = merchant_secret();
+        secret.alipay_public_key_pem_file = Some(Box::new(fixture_path("merchant_public_key.pem")));
+        let report = secret.validate();
+        assert!(matches!(
+            report.problems(),
+            [Problem::AlipayKeyIsMerchantKey]
+        ));
+    }
+}
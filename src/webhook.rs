@@ -1,99 +1,405 @@
-use chrono::{DateTime, Utc};
-use urlencoding;
+//! Verify the notifications Alipay sends and answer them.
+//!
+//! A [`WebhookDispatcher`] verifies a delivery, parses it into a [`Notification`]
+//! and hands it to the handler registered for its kind:
+//!
+//! ```no_run
+//! use alipay_global::notification::{Notification, NotificationKind};
+//! use alipay_global::webhook::WebhookDispatcher;
+//!
+//! # fn run(client: alipay_global::AlipayClient, data: alipay_global::models::WebhookData) -> Result<(), alipay_global::errors::Error> {
+//! let dispatcher = WebhookDispatcher::default().on(NotificationKind::Payment, |notification| {
+//!     if let Notification::Payment(payment) = notification {
+//!         println!("{} is {}", payment.payment_request_id, payment.result.result_status);
+//!     }
+//!     Ok(())
+//! });
+//! let acknowledgement = dispatcher.dispatch(client.protocol(), &data)?;
+//! # Ok(())
+//! # }
+//! ```
 
-use super::errors::Error;
+use std::collections::HashMap;
+
+use chrono::DateTime;
+
+use super::client::AlipayClient;
+use super::errors::{BoxError, Error, WebhookError};
 use super::models::{
-    Response, ResponseResult, ResultCode, ResultStatus,
-    AlipayClientSecret, CashierPaymentInquiry, RequestEnv,
-    NotifyPayment, WebhookData, WebhookResponse, WebhookResponseInput,
-    WebhookResponseResult
+    AlipayClientSecret, HasPrivateKey, Response, WebhookData, WebhookResponse, WebhookResponseInput,
 };
-use super::sign::{sign, verify};
-use super::response::parse_response;
+use super::notification::{Notification, NotificationKind};
+use super::sansio::{failed_acknowledgement, success_acknowledgement, AlipayProtocol};
+use super::signature::SignatureHeader;
+use super::signer::{RsaSigner, DEFAULT_KEY_VERSION};
+
+impl WebhookData {
+    /// Assemble a notification from its request line, headers and body, checking that
+    /// `request-time`, `signature` and `client-id` are each given once and well formed.
+    /// Header names are matched ignoring case, other headers are ignored.
+    pub fn from_headers<K, V>(
+        method: &str,
+        path: &str,
+        headers: impl IntoIterator<Item = (K, V)>,
+        body: &[u8],
+    ) -> Result<Self, Error>
+    where
+        K: AsRef<str>,
+        V: AsRef<[u8]>,
+    {
+        let mut request_time = None;
+        let mut signature = None;
+        let mut client_id = None;
+        for (name, value) in headers {
+            let (name, slot) = match name.as_ref() {
+                n if n.eq_ignore_ascii_case("request-time") => ("request-time", &mut request_time),
+                n if n.eq_ignore_ascii_case("signature") => ("signature", &mut signature),
+                n if n.eq_ignore_ascii_case("client-id") => ("client-id", &mut client_id),
+                _ => continue,
+            };
+            let value = std::str::from_utf8(value.as_ref())
+                .map_err(|_| invalid_header(name, "not UTF-8"))?;
+            if slot.replace(value.trim().to_string()).is_some() {
+                return Err(invalid_header(name, "given more than once"));
+            }
+        }
+
+        let request_time = required_header("request-time", request_time)?;
+        if let Err(e) = DateTime::parse_from_rfc3339(&request_time) {
+            return Err(invalid_header(
+                "request-time",
+                format!("{} is not an RFC 3339 time: {}", request_time, e),
+            ));
+        }
+        let header_signature = required_header("signature", signature)?;
+        if let Err(e) = SignatureHeader::parse(&header_signature) {
+            return Err(invalid_header("signature", e.to_string()));
+        }
+        let client_id = required_header("client-id", client_id)?;
+        let request_body = std::str::from_utf8(body).map_err(WebhookError::InvalidBody)?;
+        Ok(WebhookData {
+            method: method.to_string(),
+            path: path.to_string(),
+            request_time,
+            header_signature,
+            client_id,
+            request_body: request_body.to_string(),
+        })
+    }
+}
+
+fn required_header(name: &'static str, value: Option<String>) -> Result<String, Error> {
+    match value {
+        None => Err(WebhookError::MissingHeader(name).into()),
+        Some(value) if value.is_empty() => Err(invalid_header(name, "empty")),
+        Some(value) => Ok(value),
+    }
+}
+
+fn invalid_header(name: &'static str, reason: impl Into<String>) -> Error {
+    WebhookError::InvalidHeader {
+        name,
+        reason: reason.into(),
+    }
+    .into()
+}
 
+type Handler = Box<dyn Fn(&Notification) -> Result<(), BoxError> + Send + Sync>;
+
+/// Routes each verified notification to the handler registered for its kind
+#[derive(Default)]
+pub struct WebhookDispatcher {
+    handlers: HashMap<NotificationKind, Handler>,
+    fallback: Option<Handler>,
+}
+
+impl WebhookDispatcher {
+    /// Handle the notifications of `kind`, replacing any handler registered before
+    pub fn on(
+        mut self,
+        kind: NotificationKind,
+        handler: impl Fn(&Notification) -> Result<(), BoxError> + Send + Sync + 'static,
+    ) -> Self {
+        self.handlers.insert(kind, Box::new(handler));
+        self
+    }
+
+    /// Handle the notifications no handler is registered for.
+    /// Without it they are acknowledged without being handled.
+    pub fn otherwise(
+        mut self,
+        handler: impl Fn(&Notification) -> Result<(), BoxError> + Send + Sync + 'static,
+    ) -> Self {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Verify a delivery, hand it to its handler and build the acknowledgement to answer with.
+    ///
+    /// A notification that was handled, or received before, is acknowledged as handled.
+    /// When its handler fails, it is forgotten by the replay store and acknowledged as
+    /// failed, so Alipay delivers it again. A delivery that cannot be verified is an error.
+    pub fn dispatch(
+        &self,
+        protocol: &AlipayProtocol,
+        webhook_data: &WebhookData,
+    ) -> Result<WebhookResponse, Error> {
+        let acknowledgement = WebhookResponseInput {
+            method: webhook_data.method.clone(),
+            path: webhook_data.path.clone(),
+            client_id: webhook_data.client_id.clone(),
+        };
+        let notification = match protocol.verify_notification(webhook_data) {
+            Ok(notification) => notification,
+            Err(Error::Webhook(WebhookError::Replayed(_))) => {
+                return protocol.webhook_success_response(acknowledgement)
+            }
+            Err(e) => return Err(e),
+        };
+        let handler = self
+            .handlers
+            .get(&notification.kind())
+            .or(self.fallback.as_ref());
+        match handler.map_or(Ok(()), |handler| handler(&notification)) {
+            Ok(()) => protocol.webhook_success_response(acknowledgement),
+            Err(_) => {
+                // Alipay is asked to deliver again either way. If the key stays recorded,
+                // the next delivery is acknowledged as a replay instead of handled.
+                let _ = protocol.forget_webhook(webhook_data);
+                protocol.webhook_failed_response(acknowledgement)
+            }
+        }
+    }
+}
+
+/// Verify a notification sent by Alipay and parse its body. Neither its time nor
+/// replays are checked, use a client with a [`WebhookPolicy`](crate::replay::WebhookPolicy)
+/// for that.
 pub fn cashier_payment(
     secret: &AlipayClientSecret,
     webhook_data: WebhookData,
 ) -> Result<Response, Error> {
-    let verify = verify(
-        Some(webhook_data.path),
-        webhook_data.method.as_str(),
-        webhook_data.request_time.as_str(),
-        webhook_data.header_signature.as_str(),
-        webhook_data.client_id.as_str(),
-        webhook_data.request_body.as_str(),
-        secret
-    ).map_err(|_| Error::Fail(String::from("webhook verification failed")))?;
-    serde_json::from_str::<Response>(&webhook_data.request_body).map_err(|e| {
-        Error::Unknown(format!(
-            "Failed to parse response body into base object: {}",
-            e.to_string()
-        ))
-    })
+    AlipayClient::new(secret)?.verify_webhook(&webhook_data)
 }
 
+/// Sign the acknowledgement telling Alipay the notification was handled.
+/// Only the merchant private key of `secret` is loaded.
 pub fn success_response(
     secret: &AlipayClientSecret,
     webhook_response_in: WebhookResponseInput,
 ) -> Result<WebhookResponse, Error> {
-    let utc_now = Utc::now();
-    let response_result = ResponseResult {
-        result_code: ResultCode::SUCCESS,
-        result_status: ResultStatus::S,
-        result_message: String::from("Success")
-    };
-    let response_result_content = WebhookResponseResult {
-        result: response_result,
-    };
-    let signed = sign(
-        webhook_response_in.method.as_str(),
-        Some(webhook_response_in.path),
-        Some(webhook_response_in.client_id.to_owned()),
-        utc_now,
-        secret,
-        &response_result_content
-    );
-    let response = WebhookResponse {
-        full_signature: format!(
-            "algorithm=RSA256,keyVersion=1,signature={}",
-            urlencoding::encode(&signed)
-        ),
-        client_id: webhook_response_in.client_id,
-        response_time: utc_now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
-        body: serde_json::to_value(response_result_content).unwrap().to_string(),
-    };
-    Ok(response)
+    success_acknowledgement(&merchant_signer(secret)?, webhook_response_in)
 }
 
+/// Sign the acknowledgement asking Alipay to deliver the notification again.
+/// Only the merchant private key of `secret` is loaded.
 pub fn failed_response(
     secret: &AlipayClientSecret,
     webhook_response_in: WebhookResponseInput,
 ) -> Result<WebhookResponse, Error> {
-    let utc_now = Utc::now();
-    let response_result = ResponseResult {
-        result_code: ResultCode::PARAM_ILLEGAL,
-        result_status: ResultStatus::F,
-        result_message: String::from("The required parameters are not passed, or illegal parameters exist. For example, a non-numeric input, an invalid date, or the length and type of the parameter are wrong.")
-    };
-    let response_result_content = WebhookResponseResult {
-        result: response_result,
-    };
-    let signed = sign(
-        webhook_response_in.method.as_str(),
-        Some(webhook_response_in.path),
-        Some(webhook_response_in.client_id.to_owned()),
-        utc_now,
-        secret,
-        &response_result_content
-    );
-    let response = WebhookResponse {
-        full_signature: format!(
-            "algorithm=RSA256,keyVersion=1,signature={}",
-            urlencoding::encode(&signed)
-        ),
-        client_id: webhook_response_in.client_id,
-        response_time: utc_now.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
-        body: serde_json::to_value(response_result_content).unwrap().to_string(),
-    };
-    Ok(response)
+    failed_acknowledgement(&merchant_signer(secret)?, webhook_response_in)
+}
+
+fn merchant_signer(secret: &AlipayClientSecret) -> Result<RsaSigner, Error> {
+    let key_version = secret.key_version.as_deref().unwrap_or(DEFAULT_KEY_VERSION);
+    Ok(RsaSigner::with_key_version(
+        secret.get_private_key()?,
+        key_version,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::models::ResultStatus;
+    use crate::replay::{MemoryReplayStore, ReplayStore, WebhookPolicy};
+    use crate::test_support::{alipay_notification, merchant_secret};
+
+    fn status(response: &WebhookResponse) -> ResultStatus {
+        let body: Value = serde_json::from_str(&response.body).unwrap();
+        serde_json::from_value(body["result"]["resultStatus"].clone()).unwrap()
+    }
+
+    #[test]
+    fn routes_by_kind_and_asks_again_when_a_handler_fails() {
+        let mut protocol = AlipayProtocol::new(&merchant_secret()).unwrap();
+        protocol.set_webhook_policy(WebhookPolicy {
+            replay_store: Some(Arc::new(MemoryReplayStore::default())),
+            ..WebhookPolicy::default()
+        });
+        let refunds = Arc::new(AtomicUsize::new(0));
+        let counted = refunds.clone();
+        let dispatcher = WebhookDispatcher::default()
+            .on(NotificationKind::Refund, move |notification| {
+                assert!(matches!(notification, Notification::Refund(_)));
+                counted.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .on(
+                NotificationKind::Dispute,
+                |_| Err("database is down".into()),
+            );
+
+        let refund = alipay_notification(
+            "/notify",
+            json!({
+                "notifyType": "REFUND_RESULT",
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+                "refundRequestId": "refund-1",
+                "refundId": "r1"
+            }),
+            Utc::now(),
+        );
+        let response = dispatcher.dispatch(&protocol, &refund).unwrap();
+        assert_eq!(status(&response), ResultStatus::S);
+        // a replay is acknowledged without being handled again
+        let response = dispatcher.dispatch(&protocol, &refund).unwrap();
+        assert_eq!(status(&response), ResultStatus::S);
+        assert_eq!(refunds.load(Ordering::SeqCst), 1);
+
+        let dispute = alipay_notification(
+            "/notify",
+            json!({"disputeNotificationType": "DISPUTE_CREATED", "disputeId": "d1"}),
+            Utc::now(),
+        );
+        let response = dispatcher.dispatch(&protocol, &dispute).unwrap();
+        assert_eq!(status(&response), ResultStatus::F);
+        let response = dispatcher.dispatch(&protocol, &dispute).unwrap();
+        assert_eq!(status(&response), ResultStatus::F);
+
+        let mut tampered = refund;
+        tampered.request_body = tampered.request_body.replace("r1", "r2");
+        assert!(matches!(
+            dispatcher.dispatch(&protocol, &tampered),
+            Err(Error::Signature { .. })
+        ));
+    }
+
+    #[test]
+    fn asks_again_when_the_store_cannot_forget() {
+        struct Forgetful;
+        impl ReplayStore for Forgetful {
+            fn record(&self, _: &str, _: Option<Duration>) -> Result<bool, BoxError> {
+                Ok(true)
+            }
+            fn forget(&self, _: &str) -> Result<(), BoxError> {
+                Err("disk full".into())
+            }
+        }
+        let mut protocol = AlipayProtocol::new(&merchant_secret()).unwrap();
+        protocol.set_webhook_policy(WebhookPolicy {
+            replay_store: Some(Arc::new(Forgetful)),
+            ..WebhookPolicy::default()
+        });
+        let dispatcher = WebhookDispatcher::default().otherwise(|_| Err("database is down".into()));
+
+        let notification = alipay_notification("/notify", json!({"paymentId": "p1"}), Utc::now());
+        let response = dispatcher.dispatch(&protocol, &notification).unwrap();
+        assert_eq!(status(&response), ResultStatus::F);
+    }
+
+    #[test]
+    fn acknowledges_with_the_merchant_key_alone() {
+        let mut secret = merchant_secret();
+        secret.alipay_public_key_pem_file = None;
+        let input = || WebhookResponseInput {
+            method: String::from("POST"),
+            path: String::from("/notify"),
+            client_id: String::from("SANDBOX_TEST"),
+        };
+        assert_eq!(
+            status(&success_response(&secret, input()).unwrap()),
+            ResultStatus::S
+        );
+        assert_eq!(
+            status(&failed_response(&secret, input()).unwrap()),
+            ResultStatus::F
+        );
+    }
+
+    #[test]
+    fn cashier_payment_accepts_old_notifications() {
+        let sent = Utc::now() - chrono::Duration::days(1);
+        let notification = alipay_notification(
+            "/notify",
+            json!({
+                "notifyType": "PAYMENT_RESULT",
+                "result": {"resultCode": "SUCCESS", "resultStatus": "S", "resultMessage": "success"},
+                "paymentId": "p1"
+            }),
+            sent,
+        );
+        cashier_payment(&merchant_secret(), notification.clone()).unwrap();
+        cashier_payment(&merchant_secret(), notification).unwrap();
+    }
+
+    #[test]
+    fn builds_webhook_data_from_headers() {
+        let sent = alipay_notification("/notify", json!({"paymentId": "p1"}), Utc::now());
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("Request-Time", sent.request_time.as_str()),
+            ("SIGNATURE", sent.header_signature.as_str()),
+            ("client-id", sent.client_id.as_str()),
+        ];
+        let body = sent.request_body.as_bytes();
+        let data = WebhookData::from_headers("POST", "/notify", headers, body).unwrap();
+        AlipayProtocol::new(&merchant_secret())
+            .unwrap()
+            .verify_notification(&data)
+            .unwrap();
+
+        let error = |headers: &[(&str, &str)], body: &[u8]| match WebhookData::from_headers(
+            "POST",
+            "/notify",
+            headers.iter().copied(),
+            body,
+        ) {
+            Err(Error::Webhook(e)) => e,
+            other => panic!("expected a webhook error, got {:?}", other),
+        };
+        assert!(matches!(
+            error(&headers[..3], body),
+            WebhookError::MissingHeader("client-id")
+        ));
+        assert!(matches!(
+            error(
+                &[&headers[..], &[("Client-Id", "SANDBOX_OTHER")]].concat(),
+                body
+            ),
+            WebhookError::InvalidHeader {
+                name: "client-id",
+                ..
+            }
+        ));
+        let mut malformed = headers;
+        malformed[1].1 = "2023-07-01 08:00:00";
+        assert!(matches!(
+            error(&malformed, body),
+            WebhookError::InvalidHeader {
+                name: "request-time",
+                ..
+            }
+        ));
+        let mut malformed = headers;
+        malformed[2].1 = "algorithm=RSA256";
+        assert!(matches!(
+            error(&malformed, body),
+            WebhookError::InvalidHeader {
+                name: "signature",
+                ..
+            }
+        ));
+        assert!(matches!(
+            error(&headers, b"\xff"),
+            WebhookError::InvalidBody(_)
+        ));
+    }
 }